@@ -0,0 +1,105 @@
+/// Number of LPC coefficients, enough to follow the first few formants
+const LPC_ORDER: usize = 16;
+/// Length of each analysis frame in samples
+const FRAME_LENGTH: usize = 512;
+
+/// Fills `out` with breath noise shaped by the spectral envelope of `voiced`.
+/// Each frame is analysed with LPC and white noise is run through the resulting all-pole filter,
+/// so the noise has the same formants and roughly the same level as the voice.
+pub fn shaped_noise(voiced: &[f32], seed: u32, out: &mut [f32]) {
+    let mut noise = Noise::new(seed);
+    let mut history = [0.0_f32; LPC_ORDER];
+    let mut coefficients = [0.0_f32; LPC_ORDER];
+
+    for (frame, out) in voiced
+        .chunks(FRAME_LENGTH)
+        .zip(out.chunks_mut(FRAME_LENGTH))
+    {
+        let gain = lpc(frame, &mut coefficients);
+        for y in out.iter_mut() {
+            // all-pole filter: y[n] = g * e[n] - sum(a[k] * y[n - k])
+            *y = gain * noise.sample();
+            for (a, past) in coefficients.iter().zip(history.iter()) {
+                *y -= a * past;
+            }
+            history.rotate_right(1);
+            history[0] = *y;
+        }
+    }
+}
+
+/// Writes the linear prediction coefficients `a[1..=LPC_ORDER]` of a Hann windowed frame into `coefficients`
+/// with Levinson-Durbin, and returns the excitation gain which gives the filter output the same power as the frame.
+/// Frames are at most `FRAME_LENGTH` samples, so all the working buffers live on the stack.
+pub fn lpc(frame: &[f32], coefficients: &mut [f32; LPC_ORDER]) -> f32 {
+    let length = frame.len().min(FRAME_LENGTH);
+    let mut windowed = [0.0_f64; FRAME_LENGTH];
+    for (i, (w, s)) in windowed.iter_mut().zip(frame).enumerate() {
+        let phase = 2.0 * std::f64::consts::PI * i as f64 / (length.max(2) - 1) as f64;
+        *w = *s as f64 * (0.5 - 0.5 * phase.cos());
+    }
+    let windowed = &windowed[..length];
+
+    let mut autocorrelation = [0.0_f64; LPC_ORDER + 1];
+    for (lag, r) in autocorrelation.iter_mut().enumerate() {
+        *r = windowed
+            .iter()
+            .zip(windowed.iter().skip(lag))
+            .map(|(a, b)| a * b)
+            .sum();
+    }
+
+    *coefficients = [0.0; LPC_ORDER];
+    if autocorrelation[0] <= f64::EPSILON {
+        return 0.0;
+    }
+
+    let mut a = [0.0_f64; LPC_ORDER];
+    // the coefficients of the previous order, only the first `i` are read
+    let mut previous = [0.0_f64; LPC_ORDER];
+    let mut error = autocorrelation[0];
+    for i in 0..LPC_ORDER {
+        let mut reflection = autocorrelation[i + 1];
+        for j in 0..i {
+            reflection += a[j] * autocorrelation[i - j];
+        }
+        reflection = -reflection / error;
+
+        previous[..i].copy_from_slice(&a[..i]);
+        a[i] = reflection;
+        for j in 0..i {
+            a[j] = previous[j] + reflection * previous[i - 1 - j];
+        }
+
+        error *= 1.0 - reflection * reflection;
+        if error <= f64::EPSILON {
+            break;
+        }
+    }
+
+    for (coefficient, a) in coefficients.iter_mut().zip(a) {
+        *coefficient = a as f32;
+    }
+    // 0.375 is the mean of the squared hann window, 3.0 makes up for uniform noise having a variance of 1/3
+    (3.0 * error / (length as f64 * 0.375)).sqrt() as f32
+}
+
+/// Xorshift white noise, good enough for audio and cheap enough for the audio thread
+#[derive(Debug, Clone, Copy)]
+pub struct Noise {
+    state: u32,
+}
+
+impl Noise {
+    pub fn new(seed: u32) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    /// Returns the next sample in -1.0..1.0
+    pub fn sample(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
@@ -20,6 +20,7 @@ struct Data {
     singer_dir: Arc<Mutex<String>>,
     cur_sample: Arc<Mutex<String>>,
     lyrics: Arc<Mutex<String>>,
+    singer_flags: Arc<Mutex<String>>,
     producer: Arc<Mutex<rtrb::Producer<ThreadMessage>>>,
     debug: String,
     visualizer: Arc<VisualizerData>,
@@ -33,6 +34,9 @@ enum AppEvent {
     OpenLyricFilePicker,
     LoadLyric(PathBuf),
     SetLyricSource(i32),
+    SetSingerFlags(String),
+    OpenExportPicker,
+    ExportRecording(PathBuf),
 }
 
 impl Model for Data {
@@ -85,6 +89,28 @@ impl Model for Data {
                     self.debug = e.to_string();
                 }
             }
+            AppEvent::OpenExportPicker => {
+                cx.spawn(|cx_proxy| {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("UTAU Sequence", &["ust"])
+                        .add_filter("OpenUtau Project", &["ustx"])
+                        .save_file()
+                    {
+                        cx_proxy.emit(AppEvent::ExportRecording(path));
+                    }
+                });
+            }
+            AppEvent::ExportRecording(path) => {
+                self.debug = format!("exporting: {path:?}");
+                if let Err(e) = self
+                    .producer
+                    .lock()
+                    .unwrap()
+                    .push(ThreadMessage::ExportRecording(path.clone()))
+                {
+                    self.debug = e.to_string();
+                }
+            }
             AppEvent::SetLyricSource(source) => {
                 self.debug = format!("setting lyric source: {source}");
                 if let Err(e) = self
@@ -96,6 +122,17 @@ impl Model for Data {
                     self.debug = e.to_string();
                 }
             }
+            AppEvent::SetSingerFlags(flags) => {
+                self.debug = format!("setting singer flags: {flags}");
+                if let Err(e) = self
+                    .producer
+                    .lock()
+                    .unwrap()
+                    .push(ThreadMessage::SetSingerFlags(flags.clone()))
+                {
+                    self.debug = e.to_string();
+                }
+            }
         });
     }
 }
@@ -109,6 +146,7 @@ pub fn create(
     singer: Arc<Mutex<String>>,
     sample: Arc<Mutex<String>>,
     lyric_list: Arc<Mutex<String>>,
+    singer_flags: Arc<Mutex<String>>,
     editor_state: Arc<ViziaState>,
     producer: Arc<Mutex<rtrb::Producer<ThreadMessage>>>,
     visualizer: Arc<VisualizerData>,
@@ -122,6 +160,7 @@ pub fn create(
             singer_dir: singer.clone(),
             cur_sample: sample.clone(),
             lyrics: lyric_list.clone(),
+            singer_flags: singer_flags.clone(),
             producer: producer.clone(),
             debug: "nothing".into(),
             visualizer: visualizer.clone(),
@@ -155,6 +194,9 @@ pub fn create(
                                         }
                                         crate::lyrics::LyricSource::File => "File".to_string(),
                                         crate::lyrics::LyricSource::SysEx => "SysEx".to_string(),
+                                        crate::lyrics::LyricSource::Midi => "MIDI File".to_string(),
+                                        crate::lyrics::LyricSource::Ust => "UST".to_string(),
+                                        crate::lyrics::LyricSource::Ustx => "USTX".to_string(),
                                     }
                                 }),
                             )
@@ -162,13 +204,16 @@ pub fn create(
                             .width(Stretch(1.0))
                         },
                         |cx| {
-                            for i in 0..=2 {
+                            for i in 0..=5 {
                                 Label::new(
                                     cx,
                                     match i {
                                         0 => "Parameters - Automate Vowel and Consonant",
                                         1 => "File - Load space-separated phonemes",
                                         2 => "SysEx - Unicode bytes as SysEx messages",
+                                        3 => "MIDI File - Lyrics from a .mid/.kar file",
+                                        4 => "UST - Lyrics and expression from an UTAU project",
+                                        5 => "USTX - Lyrics and pitch from an OpenUtau project",
                                         _ => unreachable!(),
                                     },
                                 )
@@ -210,17 +255,20 @@ pub fn create(
                         Label::new(
                             cx,
                             Data::params.map(|params| {
-                                params
-                                    .lyric_settings
-                                    .lock()
-                                    .unwrap()
-                                    .clone()
-                                    .lyric_file
-                                    .path
-                                    .as_os_str()
-                                    .to_str()
-                                    .unwrap()
-                                    .to_string()
+                                let lyric_settings = params.lyric_settings.lock().unwrap();
+                                let path = match lyric_settings.lyric_source {
+                                    crate::lyrics::LyricSource::Midi => {
+                                        &lyric_settings.lyric_midi.path
+                                    }
+                                    crate::lyrics::LyricSource::Ust => {
+                                        &lyric_settings.lyric_ust.path
+                                    }
+                                    crate::lyrics::LyricSource::Ustx => {
+                                        &lyric_settings.lyric_ustx.path
+                                    }
+                                    _ => &lyric_settings.lyric_file.path,
+                                };
+                                path.as_os_str().to_str().unwrap().to_string()
                             }),
                         )
                         .class("text-container")
@@ -236,6 +284,18 @@ pub fn create(
                     .max_height(Pixels(48.0))
                     .class("lyric-scrollview");
 
+                    HStack::new(cx, |cx| {
+                        Label::new(cx, "Recording").class("heading");
+                        Button::new(
+                            cx,
+                            |cx| cx.emit(AppEvent::OpenExportPicker),
+                            |cx| Label::new(cx, "Export UST/USTX").class("add-file-text"),
+                        )
+                        .class("add-file-button");
+                    })
+                    .height(Auto)
+                    .col_between(Stretch(1.0));
+
                     Label::new(cx, "Singer Directory").class("heading");
                     Label::new(
                         cx,
@@ -243,6 +303,15 @@ pub fn create(
                     )
                     .class("text-container");
 
+                    Label::new(cx, "Singer Flags").class("heading");
+                    Textbox::new(
+                        cx,
+                        Data::singer_flags.map(|flags| flags.lock().unwrap().clone()),
+                    )
+                    .on_submit(|cx, flags, _| cx.emit(AppEvent::SetSingerFlags(flags)))
+                    .class("text-container")
+                    .width(Stretch(1.0));
+
                     Label::new(cx, "Current Sample").class("heading");
                     Label::new(
                         cx,
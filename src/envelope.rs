@@ -0,0 +1,182 @@
+/// Settings shared by every voice's envelope, read from the params once per block
+#[derive(Debug, Clone, Copy)]
+pub struct AdsrSettings {
+    /// Attack time in seconds
+    pub attack: f32,
+    /// Decay time in seconds
+    pub decay: f32,
+    /// Sustain level from 0.0 to 1.0
+    pub sustain: f32,
+    /// Release time in seconds
+    pub release: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdsrStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+/// Per-voice linear ADSR amplitude envelope
+#[derive(Debug, Clone, Copy)]
+pub struct Adsr {
+    pub stage: AdsrStage,
+    level: f32,
+    /// Level when the release started, so the release always takes the same time
+    release_level: f32,
+}
+
+impl Adsr {
+    pub fn new() -> Self {
+        Self {
+            stage: AdsrStage::Attack,
+            level: 0.0,
+            release_level: 0.0,
+        }
+    }
+
+    pub fn release(&mut self) {
+        if self.stage != AdsrStage::Done {
+            self.stage = AdsrStage::Release;
+            self.release_level = self.level;
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.stage == AdsrStage::Done
+    }
+
+    /// Advances by one sample and returns the level
+    pub fn next(&mut self, settings: &AdsrSettings, sample_rate: f32) -> f32 {
+        match self.stage {
+            AdsrStage::Attack => {
+                self.level += step(1.0, settings.attack, sample_rate);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = AdsrStage::Decay;
+                }
+            }
+            AdsrStage::Decay => {
+                self.level -= step(1.0 - settings.sustain, settings.decay, sample_rate);
+                if self.level <= settings.sustain {
+                    self.level = settings.sustain;
+                    self.stage = AdsrStage::Sustain;
+                }
+            }
+            AdsrStage::Sustain => self.level = settings.sustain,
+            AdsrStage::Release => {
+                self.level -= step(self.release_level, settings.release, sample_rate);
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = AdsrStage::Done;
+                }
+            }
+            AdsrStage::Done => self.level = 0.0,
+        }
+        self.level
+    }
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Change per sample to cover `distance` in `time` seconds, instant when `time` is 0
+fn step(distance: f32, time: f32, sample_rate: f32) -> f32 {
+    if time <= 0.0 {
+        return f32::INFINITY;
+    }
+    distance / (time * sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+    // 10 samples for each stage
+    const SETTINGS: AdsrSettings = AdsrSettings {
+        attack: 0.01,
+        decay: 0.01,
+        sustain: 0.5,
+        release: 0.01,
+    };
+
+    fn run(adsr: &mut Adsr, samples: usize) -> f32 {
+        (0..samples).fold(0.0, |_, _| adsr.next(&SETTINGS, SAMPLE_RATE))
+    }
+
+    /// Runs until the stage changes, returning how many samples that took and the level it ended on
+    fn finish_stage(adsr: &mut Adsr) -> (usize, f32) {
+        let stage = adsr.stage;
+        let mut samples = 0;
+        let mut level = 0.0;
+        while adsr.stage == stage && samples < 1000 {
+            level = adsr.next(&SETTINGS, SAMPLE_RATE);
+            samples += 1;
+        }
+        (samples, level)
+    }
+
+    #[test]
+    fn goes_through_every_stage() {
+        let mut adsr = Adsr::new();
+        assert!((run(&mut adsr, 5) - 0.5).abs() < 1e-4);
+        assert_eq!(adsr.stage, AdsrStage::Attack);
+        let (samples, level) = finish_stage(&mut adsr);
+        assert!((5..=6).contains(&samples), "{samples}");
+        assert_eq!((adsr.stage, level), (AdsrStage::Decay, 1.0));
+        let (samples, level) = finish_stage(&mut adsr);
+        assert!((10..=11).contains(&samples), "{samples}");
+        assert_eq!((adsr.stage, level), (AdsrStage::Sustain, 0.5));
+        // held for as long as the note is
+        assert_eq!(run(&mut adsr, 10_000), 0.5);
+        assert_eq!(adsr.stage, AdsrStage::Sustain);
+
+        adsr.release();
+        assert_eq!(adsr.stage, AdsrStage::Release);
+        assert!((run(&mut adsr, 5) - 0.25).abs() < 1e-4);
+        let (samples, level) = finish_stage(&mut adsr);
+        assert!((5..=6).contains(&samples), "{samples}");
+        assert_eq!(level, 0.0);
+        assert!(adsr.is_done());
+        assert_eq!(run(&mut adsr, 10), 0.0);
+        // a finished envelope stays finished
+        adsr.release();
+        assert!(adsr.is_done());
+    }
+
+    #[test]
+    fn release_during_the_attack_fades_from_where_it_is() {
+        let mut adsr = Adsr::new();
+        let level = run(&mut adsr, 4);
+        adsr.release();
+        let next = run(&mut adsr, 1);
+        assert!(next < level && next > 0.0);
+        // it takes the whole release time from the lower level too
+        assert!(run(&mut adsr, 8) > 0.0);
+        assert_eq!(run(&mut adsr, 3), 0.0);
+        assert!(adsr.is_done());
+    }
+
+    #[test]
+    fn zero_times_are_instant() {
+        let settings = AdsrSettings {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 0.3,
+            release: 0.0,
+        };
+        let mut adsr = Adsr::new();
+        assert_eq!(adsr.next(&settings, SAMPLE_RATE), 1.0);
+        assert_eq!(adsr.next(&settings, SAMPLE_RATE), 0.3);
+        adsr.release();
+        assert_eq!(adsr.next(&settings, SAMPLE_RATE), 0.0);
+        assert!(adsr.is_done());
+    }
+}
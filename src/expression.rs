@@ -0,0 +1,391 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Per-note performance data from an imported sequence, anything unset falls back to the params
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NoteExpression {
+    /// UTAU consonant velocity, from 0 to 200
+    pub velocity: Option<f32>,
+    /// Volume in percent, 100 plays the sample at its recorded level
+    pub intensity: Option<f32>,
+    pub envelope: Option<UstEnvelope>,
+    pub pitch_bend: Option<PitchBend>,
+    /// Hand-drawn pitch on top of the bend, like OpenUtau's PITD curve
+    pub pitch_deviation: Option<PitchBend>,
+    /// Length of the note in milliseconds where the end of the envelope is placed,
+    /// set when the note is released since a held note can last longer than the imported one
+    pub length: Option<f32>,
+}
+
+impl NoteExpression {
+    /// Volume from the intensity and envelope `time` milliseconds after the note started
+    pub fn gain(&self, time: f32) -> f32 {
+        let intensity = self.intensity.map_or(1.0, |intensity| intensity / 100.0);
+        let envelope = self
+            .envelope
+            .map_or(1.0, |envelope| envelope.gain(time, self.length));
+        intensity * envelope
+    }
+}
+
+/// UTAU's 5-point volume envelope, stored in the order of the `Envelope` line:
+/// `p1,p2,p3,v1,v2,v3,v4,%,p4,p5,v5`.
+/// `p1`, `p2` and `p5` are milliseconds after the previous point from the start of the note,
+/// `p4` is milliseconds before the end and `p3` before `p4`. Volumes are in percent.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct UstEnvelope {
+    pub p: [f32; 5],
+    pub v: [f32; 5],
+}
+
+impl Default for UstEnvelope {
+    /// UTAU's default envelope, `0,5,35,0,100,100,0`
+    fn default() -> Self {
+        Self {
+            p: [0.0, 5.0, 35.0, 0.0, 0.0],
+            v: [0.0, 100.0, 100.0, 0.0, 100.0],
+        }
+    }
+}
+
+impl UstEnvelope {
+    /// Parses an `Envelope` line, `None` if it has fewer than the 7 required values
+    pub fn parse(envelope: &str) -> Option<Self> {
+        let values: Vec<&str> = envelope.split(',').map(|value| value.trim()).collect();
+        if values.len() < 7 {
+            return None;
+        }
+        let number = |index: usize| {
+            values
+                .get(index)
+                .and_then(|value| value.parse::<f32>().ok())
+        };
+        let mut parsed = Self::default();
+        parsed.p[0] = number(0)?;
+        parsed.p[1] = number(1)?;
+        parsed.p[2] = number(2)?;
+        parsed.v[0] = number(3)?;
+        parsed.v[1] = number(4)?;
+        parsed.v[2] = number(5)?;
+        parsed.v[3] = number(6)?;
+        // index 7 is the `%` separator
+        parsed.p[3] = number(8).unwrap_or(0.0);
+        // without p5 the second point holds until the end section
+        parsed.p[4] = number(9).unwrap_or(0.0);
+        parsed.v[4] = number(10).unwrap_or(parsed.v[1]);
+        Some(parsed)
+    }
+
+    /// Volume `time` milliseconds into a note of `length` milliseconds, 1.0 at 100%.
+    /// Without a length only the start of the envelope is used and the last of its volumes holds,
+    /// as it does for a note which is still held.
+    pub fn gain(&self, time: f32, length: Option<f32>) -> f32 {
+        let [p1, p2, p3, p4, p5] = self.p;
+        let [v1, v2, v3, v4, v5] = self.v;
+        let mut points = [
+            (p1, v1),
+            (p1 + p2, v2),
+            (p1 + p2 + p5, v5),
+            (0.0, v3),
+            (0.0, v4),
+        ];
+        let count = match length {
+            Some(length) => {
+                points[3].0 = length - p4 - p3;
+                points[4].0 = length - p4;
+                5
+            }
+            None => 3,
+        };
+        let points = &mut points[..count];
+        // points can't go back in time when the note is shorter than the envelope
+        for i in 1..points.len() {
+            points[i].0 = points[i].0.max(points[i - 1].0);
+        }
+
+        let volume = if time <= points[0].0 {
+            points[0].1
+        } else {
+            points
+                .windows(2)
+                .find(|pair| time < pair[1].0)
+                .map(|pair| {
+                    let (from, to) = (pair[0], pair[1]);
+                    let t = (time - from.0) / (to.0 - from.0).max(f32::EPSILON);
+                    from.1 + (to.1 - from.1) * t
+                })
+                .unwrap_or(points[points.len() - 1].1)
+        };
+        volume / 100.0
+    }
+}
+
+impl fmt::Display for UstEnvelope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{},{},%,{},{},{}",
+            self.p[0],
+            self.p[1],
+            self.p[2],
+            self.v[0],
+            self.v[1],
+            self.v[2],
+            self.v[3],
+            self.p[3],
+            self.p[4],
+            self.v[4]
+        )
+    }
+}
+
+/// How the pitch moves from a bend point to the next one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BendShape {
+    /// Eases in and out, UTAU's default
+    #[default]
+    InOut,
+    Linear,
+    /// Starts slowly
+    In,
+    /// Ends slowly
+    Out,
+}
+
+impl BendShape {
+    /// Parses one of UTAU's `PBM` values
+    pub fn from_ust(shape: &str) -> Self {
+        match shape.trim() {
+            "s" => Self::Linear,
+            "j" => Self::In,
+            "r" => Self::Out,
+            _ => Self::InOut,
+        }
+    }
+
+    pub fn to_ust(self) -> &'static str {
+        match self {
+            Self::InOut => "",
+            Self::Linear => "s",
+            Self::In => "j",
+            Self::Out => "r",
+        }
+    }
+
+    /// Parses one of OpenUtau's pitch point shapes
+    pub fn from_ustx(shape: &str) -> Self {
+        match shape {
+            "l" => Self::Linear,
+            "i" => Self::In,
+            "o" => Self::Out,
+            _ => Self::InOut,
+        }
+    }
+
+    pub fn to_ustx(self) -> &'static str {
+        match self {
+            Self::InOut => "io",
+            Self::Linear => "l",
+            Self::In => "i",
+            Self::Out => "o",
+        }
+    }
+
+    /// Progress along the shape for `t` from 0.0 to 1.0
+    fn ease(self, t: f32) -> f32 {
+        use std::f32::consts::{FRAC_PI_2, PI};
+        match self {
+            Self::InOut => (1.0 - (PI * t).cos()) / 2.0,
+            Self::Linear => t,
+            Self::In => 1.0 - (FRAC_PI_2 * t).cos(),
+            Self::Out => (FRAC_PI_2 * t).sin(),
+        }
+    }
+}
+
+/// A point of a note's pitch bend
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BendPoint {
+    /// Milliseconds from the start of the note, negative before it
+    pub time: f32,
+    /// Offset from the note's pitch
+    pub cents: f32,
+    /// Shape of the curve to the next point
+    pub shape: BendShape,
+}
+
+/// A note's pitch bend as a curve through points, like UTAU's Mode2 pitch bends
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PitchBend {
+    /// Sorted by time
+    pub points: Vec<BendPoint>,
+}
+
+impl PitchBend {
+    /// Builds a bend from UTAU's `PBS`, `PBW`, `PBY` and `PBM` values, `None` without a valid `PBS`.
+    /// Pitches are in tenths of a semitone, the last point goes back to the note's pitch unless `PBY` says otherwise.
+    pub fn from_ust(pbs: &str, pbw: &str, pby: &str, pbm: &str) -> Option<Self> {
+        let (start, start_pitch) = match pbs.split_once(';') {
+            Some((start, pitch)) => (start, pitch),
+            None => (pbs, "0"),
+        };
+        let mut time = start.trim().parse::<f32>().ok()?;
+        let list = |values: &str| -> Vec<String> {
+            values
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .collect()
+        };
+        let heights = list(pby);
+        let shapes = list(pbm);
+
+        let mut points = vec![BendPoint {
+            time,
+            cents: start_pitch.trim().parse::<f32>().unwrap_or(0.0) * 10.0,
+            shape: BendShape::from_ust(shapes.first().map_or("", |shape| shape)),
+        }];
+        // a width that doesn't parse still takes its place, so the heights and shapes after it stay on their points
+        let widths = pbw.split(',').filter(|_| !pbw.trim().is_empty());
+        for (i, width) in widths.enumerate() {
+            time += width.trim().parse::<f32>().unwrap_or(0.0);
+            let height = heights
+                .get(i)
+                .and_then(|height| height.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            points.push(BendPoint {
+                time,
+                cents: height * 10.0,
+                shape: BendShape::from_ust(shapes.get(i + 1).map_or("", |shape| shape)),
+            });
+        }
+        Some(Self { points })
+    }
+
+    /// The bend as UTAU's `PBS`, `PBW`, `PBY` and `PBM` values
+    pub fn to_ust(&self) -> (String, String, String, String) {
+        let tenths = |cents: f32| (cents / 10.0).to_string();
+        let Some(first) = self.points.first() else {
+            return Default::default();
+        };
+        let rest = &self.points[1..];
+        let pbs = format!("{};{}", first.time, tenths(first.cents));
+        let pbw = rest
+            .iter()
+            .zip(self.points.iter())
+            .map(|(point, previous)| (point.time - previous.time).to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        let pby = rest
+            .iter()
+            .map(|point| tenths(point.cents))
+            .collect::<Vec<String>>()
+            .join(",");
+        // a shape is the curve to the next point, so the last point doesn't have one
+        let pbm = self.points[..self.points.len() - 1]
+            .iter()
+            .map(|point| point.shape.to_ust())
+            .collect::<Vec<&str>>()
+            .join(",");
+        (pbs, pbw, pby, pbm)
+    }
+
+    /// Pitch offset in cents `time` milliseconds after the start of the note
+    pub fn cents(&self, time: f32) -> f32 {
+        let Some(first) = self.points.first() else {
+            return 0.0;
+        };
+        if time <= first.time {
+            return first.cents;
+        }
+        for pair in self.points.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            if time < to.time {
+                let t = (time - from.time) / (to.time - from.time).max(f32::EPSILON);
+                return from.cents + (to.cents - from.cents) * from.shape.ease(t);
+            }
+        }
+        self.points.last().map_or(0.0, |point| point.cents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_note_keeps_its_sustain_volume() {
+        let envelope = UstEnvelope::parse("0,5,35,0,100,100,0").unwrap();
+        assert_eq!(envelope.gain(0.0, None), 0.0);
+        assert_eq!(envelope.gain(2.5, None), 0.5);
+        // however long the note is held
+        assert_eq!(envelope.gain(10_000.0, None), 1.0);
+    }
+
+    #[test]
+    fn end_section_plays_from_the_release() {
+        let envelope = UstEnvelope::parse("0,5,35,0,100,100,0").unwrap();
+        // released after 10 seconds, fading out over p3
+        let length = Some(10_000.0 + 35.0);
+        assert_eq!(envelope.gain(9_000.0, length), 1.0);
+        assert_eq!(envelope.gain(10_000.0, length), 1.0);
+        assert!((envelope.gain(10_017.5, length) - 0.5).abs() < 1e-3);
+        assert_eq!(envelope.gain(10_035.0, length), 0.0);
+    }
+
+    #[test]
+    fn short_notes_keep_points_in_order() {
+        let envelope = UstEnvelope::parse("10,10,35,0,100,80,0,%,0,10,60").unwrap();
+        assert_eq!(envelope.gain(20.0, None), 1.0);
+        assert_eq!(envelope.gain(30.0, None), 0.6);
+        // released before the envelope has started, the points bunch up at the release
+        let gain = envelope.gain(5.0, Some(35.0));
+        assert!((0.0..=1.0).contains(&gain));
+        assert_eq!(envelope.gain(100.0, Some(35.0)), 0.0);
+    }
+
+    #[test]
+    fn intensity_scales_the_envelope() {
+        let expression = NoteExpression {
+            intensity: Some(50.0),
+            envelope: Some(UstEnvelope::default()),
+            ..Default::default()
+        };
+        assert_eq!(expression.gain(1_000.0), 0.5);
+        assert_eq!(NoteExpression::default().gain(0.0), 1.0);
+    }
+
+    #[test]
+    fn pitch_bend_round_trips_through_ust() {
+        let bend = PitchBend::from_ust("-40;-20", "20,30.5,25", "5,-3,0", "s,r,j,").unwrap();
+        let times: Vec<f32> = bend.points.iter().map(|point| point.time).collect();
+        assert_eq!(times, [-40.0, -20.0, 10.5, 35.5]);
+        let shapes: Vec<BendShape> = bend.points.iter().map(|point| point.shape).collect();
+        assert_eq!(
+            shapes,
+            [
+                BendShape::Linear,
+                BendShape::Out,
+                BendShape::In,
+                BendShape::InOut
+            ]
+        );
+
+        let (pbs, pbw, pby, pbm) = bend.to_ust();
+        assert_eq!(pbm.split(',').count(), 3);
+        assert_eq!(PitchBend::from_ust(&pbs, &pbw, &pby, &pbm).unwrap(), bend);
+    }
+
+    #[test]
+    fn bad_pitch_bend_widths_keep_their_place() {
+        let bend = PitchBend::from_ust("0;0", "10,x,20", "1,2,3", ",,r").unwrap();
+        let heights: Vec<f32> = bend.points.iter().map(|point| point.cents).collect();
+        assert_eq!(heights, [0.0, 10.0, 20.0, 30.0]);
+        assert_eq!(bend.points[2].time, bend.points[1].time);
+        assert_eq!(bend.points[3].shape, BendShape::InOut);
+        assert_eq!(
+            PitchBend::from_ust("0", "", "", "").unwrap().points.len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// UTAU resampler flags, parsed from strings like `g-5B60t12P86`.
+/// Flags which aren't set are `None` so that per-note flags can be layered over the singer's flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Flags {
+    /// `g`: gender (formant shift), -100 to 100. Positive values lower the formants.
+    pub gender: Option<i32>,
+    /// `B`: breathiness, 0 to 100. 50 is neutral, higher values mix in more breath noise.
+    pub breathiness: Option<i32>,
+    /// `t`: pitch offset in cents
+    pub pitch_offset: Option<i32>,
+    /// `P`: peak compression, 0 to 100
+    pub peak_compression: Option<i32>,
+    /// `H`: parsed so it survives round trips, not used by this engine
+    pub h: Option<i32>,
+    /// `Y`: parsed so it survives round trips, not used by this engine
+    pub y: Option<i32>,
+}
+
+impl Flags {
+    pub fn parse(flags: &str) -> Self {
+        let mut parsed = Self::default();
+        let mut chars = flags.chars().peekable();
+
+        while let Some(flag) = chars.next() {
+            if !flag.is_ascii_alphabetic() {
+                continue;
+            }
+            let mut value = String::new();
+            while let Some(c) = chars.peek() {
+                if c.is_ascii_digit() || (value.is_empty() && (*c == '-' || *c == '+')) {
+                    value.push(*c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let value = value.parse::<i32>().ok();
+
+            match flag {
+                'g' => parsed.gender = value.map(|v| v.clamp(-100, 100)),
+                'B' => parsed.breathiness = value.map(|v| v.clamp(0, 100)),
+                't' => parsed.pitch_offset = value,
+                'P' => parsed.peak_compression = value.map(|v| v.clamp(0, 100)),
+                'H' => parsed.h = value,
+                'Y' => parsed.y = value,
+                _ => nih_plug::nih_log!("Ignoring unsupported flag: {}", flag),
+            }
+        }
+
+        parsed
+    }
+
+    /// Layers `other` over these flags, any flag set in `other` wins
+    pub fn merge(&self, other: &Flags) -> Self {
+        Self {
+            gender: other.gender.or(self.gender),
+            breathiness: other.breathiness.or(self.breathiness),
+            pitch_offset: other.pitch_offset.or(self.pitch_offset),
+            peak_compression: other.peak_compression.or(self.peak_compression),
+            h: other.h.or(self.h),
+            y: other.y.or(self.y),
+        }
+    }
+
+    /// Formant shift in semitones, g100 lowers the formants by 6 semitones
+    pub fn formant_shift(&self) -> f32 {
+        -(self.gender.unwrap_or(0) as f32) * 0.06
+    }
+
+    /// Amount of breath noise to mix in, from 0.0 to 1.0
+    pub fn breath_mix(&self) -> f32 {
+        ((self.breathiness.unwrap_or(50) as f32 - 50.0) / 50.0).clamp(0.0, 1.0)
+    }
+
+    pub fn pitch_offset_semitones(&self) -> f32 {
+        self.pitch_offset.unwrap_or(0) as f32 / 100.0
+    }
+
+    /// Peak compression amount, from 0.0 to 1.0
+    pub fn peak_compression(&self) -> f32 {
+        self.peak_compression.unwrap_or(0) as f32 / 100.0
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [
+            ('g', self.gender),
+            ('B', self.breathiness),
+            ('t', self.pitch_offset),
+            ('P', self.peak_compression),
+            ('H', self.h),
+            ('Y', self.y),
+        ];
+        for (flag, value) in flags {
+            if let Some(value) = value {
+                write!(f, "{}{}", flag, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_multiple_flags() {
+        let flags = Flags::parse("g-5B60t12P86H20Y70");
+        assert_eq!(flags.gender, Some(-5));
+        assert_eq!(flags.breathiness, Some(60));
+        assert_eq!(flags.pitch_offset, Some(12));
+        assert_eq!(flags.peak_compression, Some(86));
+        assert_eq!(flags.h, Some(20));
+        assert_eq!(flags.y, Some(70));
+        assert_eq!(flags.to_string(), "g-5B60t12P86H20Y70");
+    }
+
+    #[test]
+    fn parses_signed_values() {
+        assert_eq!(Flags::parse("t-30").pitch_offset, Some(-30));
+        assert_eq!(Flags::parse("t+30").pitch_offset, Some(30));
+        assert_eq!(Flags::parse("g+20").gender, Some(20));
+        // a sign is only read at the start of a value
+        assert_eq!(Flags::parse("t3-0").pitch_offset, Some(3));
+        // values are clamped to the flag's range
+        assert_eq!(Flags::parse("g-300B150").gender, Some(-100));
+        assert_eq!(Flags::parse("g-300B150").breathiness, Some(100));
+    }
+
+    #[test]
+    fn skips_unknown_flags() {
+        let flags = Flags::parse("Mt50e g10");
+        assert_eq!(flags.pitch_offset, Some(50));
+        assert_eq!(flags.gender, Some(10));
+        assert_eq!(Flags::parse("X10Z-4"), Flags::default());
+    }
+
+    #[test]
+    fn empty_input_sets_nothing() {
+        assert_eq!(Flags::parse(""), Flags::default());
+        assert_eq!(Flags::parse("   "), Flags::default());
+        // a flag without a value isn't set either
+        assert_eq!(Flags::parse("g").gender, None);
+        assert_eq!(Flags::default().to_string(), "");
+    }
+
+    #[test]
+    fn merge_prefers_the_other_flags() {
+        let singer = Flags::parse("g10B60");
+        let note = Flags::parse("g-10t5");
+        assert_eq!(singer.merge(&note), Flags::parse("g-10B60t5"));
+    }
+}
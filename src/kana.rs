@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+
+use crate::romaji;
+
+/// One mora of a CV voicebank, in each of the scripts aliases are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mora {
+    pub hiragana: &'static str,
+    pub katakana: &'static str,
+    /// Hepburn, with the spellings UTAU romaji banks use for the extended moras
+    pub romaji: &'static str,
+    /// Vowel the mora ends on, `n` for ん and `None` for っ
+    pub vowel: Option<char>,
+}
+
+const fn mora(
+    hiragana: &'static str,
+    katakana: &'static str,
+    romaji: &'static str,
+    vowel: Option<char>,
+) -> Mora {
+    Mora {
+        hiragana,
+        katakana,
+        romaji,
+        vowel,
+    }
+}
+
+/// Every mora found in standard CV banks. Where two kana share a romaji spelling
+/// the common one comes first, so looking up by romaji finds it.
+#[rustfmt::skip]
+pub const MORAS: &[Mora] = &[
+    mora("あ", "ア", "a", Some('a')), mora("い", "イ", "i", Some('i')), mora("う", "ウ", "u", Some('u')), mora("え", "エ", "e", Some('e')), mora("お", "オ", "o", Some('o')),
+    mora("か", "カ", "ka", Some('a')), mora("き", "キ", "ki", Some('i')), mora("く", "ク", "ku", Some('u')), mora("け", "ケ", "ke", Some('e')), mora("こ", "コ", "ko", Some('o')),
+    mora("さ", "サ", "sa", Some('a')), mora("し", "シ", "shi", Some('i')), mora("す", "ス", "su", Some('u')), mora("せ", "セ", "se", Some('e')), mora("そ", "ソ", "so", Some('o')),
+    mora("た", "タ", "ta", Some('a')), mora("ち", "チ", "chi", Some('i')), mora("つ", "ツ", "tsu", Some('u')), mora("て", "テ", "te", Some('e')), mora("と", "ト", "to", Some('o')),
+    mora("な", "ナ", "na", Some('a')), mora("に", "ニ", "ni", Some('i')), mora("ぬ", "ヌ", "nu", Some('u')), mora("ね", "ネ", "ne", Some('e')), mora("の", "ノ", "no", Some('o')),
+    mora("は", "ハ", "ha", Some('a')), mora("ひ", "ヒ", "hi", Some('i')), mora("ふ", "フ", "fu", Some('u')), mora("へ", "ヘ", "he", Some('e')), mora("ほ", "ホ", "ho", Some('o')),
+    mora("ま", "マ", "ma", Some('a')), mora("み", "ミ", "mi", Some('i')), mora("む", "ム", "mu", Some('u')), mora("め", "メ", "me", Some('e')), mora("も", "モ", "mo", Some('o')),
+    mora("や", "ヤ", "ya", Some('a')), mora("ゆ", "ユ", "yu", Some('u')), mora("いぇ", "イェ", "ye", Some('e')), mora("よ", "ヨ", "yo", Some('o')),
+    mora("ら", "ラ", "ra", Some('a')), mora("り", "リ", "ri", Some('i')), mora("る", "ル", "ru", Some('u')), mora("れ", "レ", "re", Some('e')), mora("ろ", "ロ", "ro", Some('o')),
+    mora("わ", "ワ", "wa", Some('a')), mora("うぃ", "ウィ", "wi", Some('i')), mora("うぇ", "ウェ", "we", Some('e')), mora("を", "ヲ", "wo", Some('o')), mora("うぉ", "ウォ", "who", Some('o')),
+    mora("ゐ", "ヰ", "wi", Some('i')), mora("ゑ", "ヱ", "we", Some('e')),
+    mora("ん", "ン", "n", Some('n')),
+    mora("が", "ガ", "ga", Some('a')), mora("ぎ", "ギ", "gi", Some('i')), mora("ぐ", "グ", "gu", Some('u')), mora("げ", "ゲ", "ge", Some('e')), mora("ご", "ゴ", "go", Some('o')),
+    mora("ざ", "ザ", "za", Some('a')), mora("じ", "ジ", "ji", Some('i')), mora("ず", "ズ", "zu", Some('u')), mora("ぜ", "ゼ", "ze", Some('e')), mora("ぞ", "ゾ", "zo", Some('o')),
+    mora("だ", "ダ", "da", Some('a')), mora("ぢ", "ヂ", "ji", Some('i')), mora("づ", "ヅ", "zu", Some('u')), mora("で", "デ", "de", Some('e')), mora("ど", "ド", "do", Some('o')),
+    mora("ば", "バ", "ba", Some('a')), mora("び", "ビ", "bi", Some('i')), mora("ぶ", "ブ", "bu", Some('u')), mora("べ", "ベ", "be", Some('e')), mora("ぼ", "ボ", "bo", Some('o')),
+    mora("ぱ", "パ", "pa", Some('a')), mora("ぴ", "ピ", "pi", Some('i')), mora("ぷ", "プ", "pu", Some('u')), mora("ぺ", "ペ", "pe", Some('e')), mora("ぽ", "ポ", "po", Some('o')),
+    mora("きゃ", "キャ", "kya", Some('a')), mora("きゅ", "キュ", "kyu", Some('u')), mora("きぇ", "キェ", "kye", Some('e')), mora("きょ", "キョ", "kyo", Some('o')),
+    mora("しゃ", "シャ", "sha", Some('a')), mora("しゅ", "シュ", "shu", Some('u')), mora("しぇ", "シェ", "she", Some('e')), mora("しょ", "ショ", "sho", Some('o')),
+    mora("ちゃ", "チャ", "cha", Some('a')), mora("ちゅ", "チュ", "chu", Some('u')), mora("ちぇ", "チェ", "che", Some('e')), mora("ちょ", "チョ", "cho", Some('o')),
+    mora("にゃ", "ニャ", "nya", Some('a')), mora("にゅ", "ニュ", "nyu", Some('u')), mora("にぇ", "ニェ", "nye", Some('e')), mora("にょ", "ニョ", "nyo", Some('o')),
+    mora("ひゃ", "ヒャ", "hya", Some('a')), mora("ひゅ", "ヒュ", "hyu", Some('u')), mora("ひぇ", "ヒェ", "hye", Some('e')), mora("ひょ", "ヒョ", "hyo", Some('o')),
+    mora("みゃ", "ミャ", "mya", Some('a')), mora("みゅ", "ミュ", "myu", Some('u')), mora("みぇ", "ミェ", "mye", Some('e')), mora("みょ", "ミョ", "myo", Some('o')),
+    mora("りゃ", "リャ", "rya", Some('a')), mora("りゅ", "リュ", "ryu", Some('u')), mora("りぇ", "リェ", "rye", Some('e')), mora("りょ", "リョ", "ryo", Some('o')),
+    mora("ぎゃ", "ギャ", "gya", Some('a')), mora("ぎゅ", "ギュ", "gyu", Some('u')), mora("ぎぇ", "ギェ", "gye", Some('e')), mora("ぎょ", "ギョ", "gyo", Some('o')),
+    mora("じゃ", "ジャ", "ja", Some('a')), mora("じゅ", "ジュ", "ju", Some('u')), mora("じぇ", "ジェ", "je", Some('e')), mora("じょ", "ジョ", "jo", Some('o')),
+    mora("びゃ", "ビャ", "bya", Some('a')), mora("びゅ", "ビュ", "byu", Some('u')), mora("びぇ", "ビェ", "bye", Some('e')), mora("びょ", "ビョ", "byo", Some('o')),
+    mora("ぴゃ", "ピャ", "pya", Some('a')), mora("ぴゅ", "ピュ", "pyu", Some('u')), mora("ぴぇ", "ピェ", "pye", Some('e')), mora("ぴょ", "ピョ", "pyo", Some('o')),
+    mora("ふぁ", "ファ", "fa", Some('a')), mora("ふぃ", "フィ", "fi", Some('i')), mora("ふぇ", "フェ", "fe", Some('e')), mora("ふぉ", "フォ", "fo", Some('o')), mora("ふゅ", "フュ", "fyu", Some('u')),
+    mora("ゔぁ", "ヴァ", "va", Some('a')), mora("ゔぃ", "ヴィ", "vi", Some('i')), mora("ゔ", "ヴ", "vu", Some('u')), mora("ゔぇ", "ヴェ", "ve", Some('e')), mora("ゔぉ", "ヴォ", "vo", Some('o')),
+    mora("つぁ", "ツァ", "tsa", Some('a')), mora("つぃ", "ツィ", "tsi", Some('i')), mora("つぇ", "ツェ", "tse", Some('e')), mora("つぉ", "ツォ", "tso", Some('o')),
+    mora("てぃ", "ティ", "ti", Some('i')), mora("とぅ", "トゥ", "tu", Some('u')), mora("てゅ", "テュ", "tyu", Some('u')),
+    mora("でぃ", "ディ", "di", Some('i')), mora("どぅ", "ドゥ", "du", Some('u')), mora("でゅ", "デュ", "dyu", Some('u')),
+    mora("すぃ", "スィ", "swi", Some('i')), mora("ずぃ", "ズィ", "zwi", Some('i')),
+    mora("ぁ", "ァ", "xa", Some('a')), mora("ぃ", "ィ", "xi", Some('i')), mora("ぅ", "ゥ", "xu", Some('u')), mora("ぇ", "ェ", "xe", Some('e')), mora("ぉ", "ォ", "xo", Some('o')),
+    mora("っ", "ッ", "xtsu", None),
+];
+
+/// Rows of the Consonant param with the kana for each Vowel param value (a, i, u, e, o).
+/// Cells without a mora of their own repeat the nearest one, and ん and っ fill their whole row.
+#[rustfmt::skip]
+pub const GRID: &[(&str, [&str; 5])] = &[
+    ("", ["あ", "い", "う", "え", "お"]),
+    ("k", ["か", "き", "く", "け", "こ"]),
+    ("s", ["さ", "し", "す", "せ", "そ"]),
+    ("t", ["た", "ち", "つ", "て", "と"]),
+    ("n", ["な", "に", "ぬ", "ね", "の"]),
+    ("h", ["は", "ひ", "ふ", "へ", "ほ"]),
+    ("m", ["ま", "み", "む", "め", "も"]),
+    ("y", ["や", "い", "ゆ", "いぇ", "よ"]),
+    ("r", ["ら", "り", "る", "れ", "ろ"]),
+    ("w", ["わ", "うぃ", "う", "うぇ", "を"]),
+    ("g", ["が", "ぎ", "ぐ", "げ", "ご"]),
+    ("z", ["ざ", "じ", "ず", "ぜ", "ぞ"]),
+    ("d", ["だ", "ぢ", "づ", "で", "ど"]),
+    ("b", ["ば", "び", "ぶ", "べ", "ぼ"]),
+    ("p", ["ぱ", "ぴ", "ぷ", "ぺ", "ぽ"]),
+    ("ky", ["きゃ", "き", "きゅ", "きぇ", "きょ"]),
+    ("sh", ["しゃ", "し", "しゅ", "しぇ", "しょ"]),
+    ("ch", ["ちゃ", "ち", "ちゅ", "ちぇ", "ちょ"]),
+    ("ny", ["にゃ", "に", "にゅ", "にぇ", "にょ"]),
+    ("hy", ["ひゃ", "ひ", "ひゅ", "ひぇ", "ひょ"]),
+    ("my", ["みゃ", "み", "みゅ", "みぇ", "みょ"]),
+    ("ry", ["りゃ", "り", "りゅ", "りぇ", "りょ"]),
+    ("gy", ["ぎゃ", "ぎ", "ぎゅ", "ぎぇ", "ぎょ"]),
+    ("j", ["じゃ", "じ", "じゅ", "じぇ", "じょ"]),
+    ("by", ["びゃ", "び", "びゅ", "びぇ", "びょ"]),
+    ("py", ["ぴゃ", "ぴ", "ぴゅ", "ぴぇ", "ぴょ"]),
+    ("f", ["ふぁ", "ふぃ", "ふ", "ふぇ", "ふぉ"]),
+    ("v", ["ゔぁ", "ゔぃ", "ゔ", "ゔぇ", "ゔぉ"]),
+    ("ts", ["つぁ", "つぃ", "つ", "つぇ", "つぉ"]),
+    ("t'", ["た", "てぃ", "とぅ", "て", "と"]),
+    ("d'", ["だ", "でぃ", "どぅ", "で", "ど"]),
+    ("w (old)", ["わ", "ゐ", "う", "ゑ", "を"]),
+    ("small", ["ぁ", "ぃ", "ぅ", "ぇ", "ぉ"]),
+    ("n'", ["ん", "ん", "ん", "ん", "ん"]),
+    ("cl", ["っ", "っ", "っ", "っ", "っ"]),
+];
+
+/// Rows at the start of `GRID` which the Consonant param picks from, the others are picked by Extended Consonant
+pub const BASIC_ROWS: usize = 15;
+
+/// Finds a mora by its hiragana or katakana
+pub fn find(kana: &str) -> Option<&'static Mora> {
+    MORAS
+        .iter()
+        .find(|mora| mora.hiragana == kana || mora.katakana == kana)
+}
+
+/// Finds a mora by its romaji, ignoring case
+pub fn find_romaji(romaji: &str) -> Option<&'static Mora> {
+    MORAS
+        .iter()
+        .find(|mora| mora.romaji.eq_ignore_ascii_case(romaji))
+}
+
+/// The mora in a cell of the grid, `None` outside of it
+pub fn grid(consonant: usize, vowel: usize) -> Option<&'static Mora> {
+    GRID.get(consonant)
+        .and_then(|(_, row)| row.get(vowel))
+        .and_then(|kana| find(kana))
+}
+
+/// Vowel of the last mora in a lyric, including a trailing small kana like the ゃ of きゃ
+pub fn last_vowel(lyric: &str) -> Option<char> {
+    let chars: Vec<char> = lyric.trim().chars().collect();
+    (1..=chars.len().min(2))
+        .rev()
+        .find_map(|count| find(&chars[chars.len() - count..].iter().collect::<String>()))
+        .and_then(|mora| mora.vowel)
+}
+
+/// Encodes a lyric as Shift-JIS, leaving out characters it has no code for.
+/// Hiragana missing from Shift-JIS, like ゔ, are written in katakana instead.
+pub fn to_shift_jis(lyric: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut buffer = [0; 4];
+    for c in lyric.chars() {
+        let text = c.encode_utf8(&mut buffer);
+        let katakana = find(text).map(|mora| mora.katakana);
+        for text in std::iter::once(&*text).chain(katakana) {
+            let (encoded, _, had_errors) = encoding_rs::SHIFT_JIS.encode(text);
+            if !had_errors {
+                bytes.extend_from_slice(&encoded);
+                break;
+            }
+        }
+    }
+    bytes
+}
+
+/// Offset from a hiragana to its katakana
+const KATAKANA_OFFSET: u32 = 0x60;
+
+fn is_hiragana(c: char) -> bool {
+    ('\u{3041}'..='\u{3096}').contains(&c)
+}
+
+fn is_katakana(c: char) -> bool {
+    ('\u{30A1}'..='\u{30F6}').contains(&c)
+}
+
+pub fn to_katakana(text: &str) -> String {
+    text.chars()
+        .map(|c| match is_hiragana(c) {
+            true => char::from_u32(c as u32 + KATAKANA_OFFSET).unwrap_or(c),
+            false => c,
+        })
+        .collect()
+}
+
+pub fn to_hiragana(text: &str) -> String {
+    text.chars()
+        .map(|c| match is_katakana(c) {
+            true => char::from_u32(c as u32 - KATAKANA_OFFSET).unwrap_or(c),
+            false => c,
+        })
+        .collect()
+}
+
+/// Script a voicebank's aliases are written in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Script {
+    #[default]
+    Hiragana,
+    Katakana,
+    Romaji,
+}
+
+impl Script {
+    /// Script of the first kana or Latin letter in the text, `None` if it has neither
+    pub fn of(text: &str) -> Option<Self> {
+        text.chars().find_map(|c| {
+            if is_hiragana(c) {
+                Some(Self::Hiragana)
+            } else if is_katakana(c) {
+                Some(Self::Katakana)
+            } else if c.is_ascii_alphabetic() {
+                Some(Self::Romaji)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Writes a lyric in this script, whichever script it was written in
+    pub fn convert(self, lyric: &str) -> String {
+        match self {
+            Self::Hiragana => to_hiragana(&romaji::to_kana(lyric)),
+            Self::Katakana => to_katakana(&romaji::to_kana(lyric)),
+            Self::Romaji => romaji::to_romaji(lyric),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_rows_are_unique_and_in_the_table() {
+        for (i, (label, row)) in GRID.iter().enumerate() {
+            assert!(
+                GRID[i + 1..].iter().all(|(other, _)| other != label),
+                "duplicate row {label}"
+            );
+            for kana in row {
+                assert!(find(kana).is_some(), "{kana} isn't in the table");
+            }
+        }
+        // the Consonant param's rows come first
+        assert_eq!(GRID[BASIC_ROWS - 1].0, "p");
+    }
+
+    #[test]
+    fn finds_moras_in_every_script() {
+        assert_eq!(find("きゃ").map(|mora| mora.romaji), Some("kya"));
+        assert_eq!(find("キャ").map(|mora| mora.hiragana), Some("きゃ"));
+        assert_eq!(find_romaji("SHI").map(|mora| mora.hiragana), Some("し"));
+        assert!(find("漢").is_none());
+        assert_eq!(grid(1, 0).map(|mora| mora.hiragana), Some("か"));
+        assert_eq!(grid(BASIC_ROWS, 4).map(|mora| mora.hiragana), Some("きょ"));
+        assert!(grid(GRID.len(), 0).is_none());
+        assert!(grid(0, 5).is_none());
+    }
+
+    #[test]
+    fn last_vowel_includes_small_kana() {
+        assert_eq!(last_vowel("きゃ"), Some('a'));
+        assert_eq!(last_vowel("さくら "), Some('a'));
+        assert_eq!(last_vowel("ほん"), Some('n'));
+        assert_eq!(last_vowel("っ"), None);
+        assert_eq!(last_vowel(""), None);
+    }
+
+    #[test]
+    fn shift_jis_falls_back_to_katakana() {
+        assert_eq!(to_shift_jis("あ"), [0x82, 0xa0]);
+        assert_eq!(to_shift_jis("ゔ"), to_shift_jis("ヴ"));
+        assert_eq!(to_shift_jis("a😀"), [0x61]);
+    }
+
+    #[test]
+    fn converts_between_scripts() {
+        assert_eq!(to_katakana("きゃ ん a"), "キャ ン a");
+        assert_eq!(to_hiragana("キャ ン a"), "きゃ ん a");
+        assert_eq!(Script::of("- かa"), Some(Script::Hiragana));
+        assert_eq!(Script::of("A3 カ"), Some(Script::Romaji));
+        assert_eq!(Script::of("123 息"), None);
+        assert_eq!(Script::Katakana.convert("kya"), "キャ");
+        assert_eq!(Script::Hiragana.convert("キャ"), "きゃ");
+        assert_eq!(Script::Romaji.convert("きゃ"), "kya");
+        assert_eq!(Script::Hiragana.convert("息"), "息");
+    }
+}
@@ -0,0 +1,84 @@
+/// Grace window after a note-on in which a lyric still switches that note,
+/// for hosts which send the lyric just after the note instead of before it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LateLyricWindow {
+    /// Time in samples, note and velocity of the last note-on, `None` once the window has closed
+    note_on: Option<(u64, u8, f32)>,
+}
+
+impl LateLyricWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn note_on(&mut self, time: u64, note: u8, velocity: f32) {
+        self.note_on = Some((time, note, velocity));
+    }
+
+    /// Closes the window when its note is released
+    pub fn note_off(&mut self, note: u8) {
+        if matches!(self.note_on, Some((_, held, _)) if held == note) {
+            self.note_on = None;
+        }
+    }
+
+    /// Closes the window once more than `grace` samples have passed since the note-on
+    pub fn expire(&mut self, time: u64, grace: u64) {
+        if matches!(self.note_on, Some((note_time, _, _)) if time.saturating_sub(note_time) > grace)
+        {
+            self.note_on = None;
+        }
+    }
+
+    /// Note and velocity that a lyric arriving at `time` switches, `None` if the window has closed.
+    /// This closes the window, so any later lyric waits for the next note.
+    pub fn take(&mut self, time: u64, grace: u64) -> Option<(u8, f32)> {
+        self.expire(time, grace);
+        self.note_on
+            .take()
+            .map(|(_, note, velocity)| (note, velocity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRACE: u64 = 441;
+
+    #[test]
+    fn lyric_within_grace_window_switches_the_note() {
+        let mut window = LateLyricWindow::new();
+        window.note_on(1000, 60, 0.5);
+        assert_eq!(window.take(1000 + GRACE, GRACE), Some((60, 0.5)));
+        // only the first lyric switches it
+        assert_eq!(window.take(1000 + GRACE, GRACE), None);
+    }
+
+    #[test]
+    fn lyric_after_grace_window_applies_to_next_note_only() {
+        let mut window = LateLyricWindow::new();
+        window.note_on(1000, 60, 0.5);
+        assert_eq!(window.take(1001 + GRACE, GRACE), None);
+
+        // the lyric is left for the next note, which opens a window of its own
+        window.note_on(5000, 62, 0.8);
+        assert_eq!(window.take(5010, GRACE), Some((62, 0.8)));
+    }
+
+    #[test]
+    fn window_closes_on_note_off_and_expiry() {
+        let mut window = LateLyricWindow::new();
+        window.note_on(0, 60, 0.5);
+        // releasing another note leaves it open
+        window.note_off(64);
+        window.note_off(60);
+        assert_eq!(window.take(10, GRACE), None);
+
+        window.note_on(0, 60, 0.5);
+        window.expire(GRACE, GRACE);
+        assert!(window.note_on.is_some());
+        window.expire(GRACE + 1, GRACE);
+        assert_eq!(window.take(GRACE + 1, u64::MAX), None);
+    }
+}
@@ -10,7 +10,6 @@ use std::{
     sync::{Arc, Mutex},
     vec,
 };
-use tdpsola::{AlternatingHann, Speed, TdpsolaAnalysis, TdpsolaSynthesis};
 
 use nih_plug::prelude::*;
 mod editor_vizia;
@@ -31,12 +30,62 @@ use lyrics::*;
 mod sysex;
 use sysex::*;
 
+mod nsx1;
+
+mod timeline;
+
+mod midi_file;
+use midi_file::*;
+
+mod expression;
+
+mod ust;
+use ust::*;
+
+mod ustx;
+use ustx::*;
+
+mod recording;
+use recording::*;
+
 mod phoneme;
 use phoneme::*;
 
+mod kana;
+
+mod romaji;
+
 mod midi;
 use midi::*;
 
+mod flags;
+use flags::*;
+
+mod synth;
+
+mod breath;
+
+mod vibrato;
+use vibrato::*;
+
+mod portamento;
+use portamento::*;
+
+mod note_stack;
+use note_stack::*;
+
+mod late_lyric;
+use late_lyric::*;
+
+mod pitch_curve;
+use pitch_curve::*;
+
+mod envelope;
+use envelope::*;
+
+mod sustain;
+use sustain::*;
+
 /// A loaded sample stored as a vec of samples in the form:
 /// [
 ///     [a, a, a, ...],
@@ -45,6 +94,8 @@ use midi::*;
 pub struct LoadedSample {
     samples: Vec<Vec<f32>>,
     frequency: f32,
+    /// Sustain loops of the oto entries using this sample, keyed by alias
+    loops: HashMap<Vec<u8>, SustainLoops>,
 }
 
 #[derive(Clone)]
@@ -53,6 +104,8 @@ pub enum ThreadMessage {
     RemoveSinger(PathBuf),
     LoadLyric(PathBuf),
     SetLyricSource(i32),
+    SetSingerFlags(String),
+    ExportRecording(PathBuf),
 }
 
 /// Main plugin struct
@@ -64,10 +117,28 @@ pub struct Plutau {
     pub consumer: RefCell<Option<rtrb::Consumer<ThreadMessage>>>,
     pub visualizer: Arc<VisualizerData>,
     pub sample_frequency: f32,
-    pub midi_frequency: f32,
     pub pitch_bend: f32,
     pub note: u8,
     pub lyric: String,
+    /// Last value of the breath CC, from 0.0 to 1.0
+    pub breath_cc_value: f32,
+    /// Last value of the mod wheel (CC1), from 0.0 to 1.0
+    pub mod_wheel: f32,
+    pub note_stack: NoteStack,
+    /// The singer flags string, parsed when it changes
+    pub singer_flags: Flags,
+    /// Samples processed since the plugin started, used to time events across blocks
+    pub sample_clock: u64,
+    /// The last note-on, for switching to a lyric that arrives just after it
+    pub late_lyric: LateLyricWindow,
+    /// Song position in beats at the start of the block, `None` when the transport isn't playing
+    pub song_position: Option<f64>,
+    /// Host tempo, 120 BPM if the host doesn't say
+    pub tempo: f64,
+    /// Notes captured while Record is on
+    pub recording: Recording,
+    /// Whether Record was on in the last block, turning it on starts a new recording
+    pub was_recording: bool,
 }
 
 impl Default for Plutau {
@@ -80,10 +151,19 @@ impl Default for Plutau {
             sample_rate: 44100.0,
             visualizer: Arc::new(VisualizerData::new()),
             sample_frequency: 440.0,
-            midi_frequency: 440.0,
             pitch_bend: 0.0,
             note: 0,
             lyric: String::new(),
+            breath_cc_value: 0.0,
+            mod_wheel: 0.0,
+            note_stack: NoteStack::new(),
+            singer_flags: Flags::default(),
+            sample_clock: 0,
+            late_lyric: LateLyricWindow::new(),
+            song_position: None,
+            tempo: 120.0,
+            recording: Recording::new(),
+            was_recording: false,
         }
     }
 }
@@ -101,6 +181,8 @@ pub struct PlutauParams {
     pub oto: Mutex<Oto>,
     #[persist = "lyric-settings"]
     pub lyric_settings: Arc<Mutex<LyricSettings>>,
+    #[persist = "singer-flags"]
+    pub singer_flags: Arc<Mutex<String>>,
 
     pub singer: Arc<Mutex<String>>,
     pub cur_sample: Arc<Mutex<String>>,
@@ -111,17 +193,82 @@ pub struct PlutauParams {
 
     #[id = "consonant"]
     pub consonant: IntParam,
+    /// Row of the kana grid after the basic ones, 0 leaves the row to `consonant`
+    #[id = "extended-consonant"]
+    pub extended_consonant: IntParam,
 
     #[id = "gain"]
     pub gain: FloatParam,
-    #[id = "instant-cutoff"]
-    pub instant_cutoff: BoolParam,
     #[id = "bend-range"]
     pub bend_range: FloatParam,
     #[id = "crossfade-length"]
     pub crossfade_length: IntParam,
     #[id = "crossfade-on"]
     pub crossfade_on: BoolParam,
+    #[id = "formant"]
+    pub formant: FloatParam,
+    #[id = "breathiness"]
+    pub breathiness: FloatParam,
+    #[id = "breath-cc"]
+    pub breath_cc: IntParam,
+    #[id = "vibrato-rate"]
+    pub vibrato_rate: FloatParam,
+    #[id = "vibrato-depth"]
+    pub vibrato_depth: FloatParam,
+    #[id = "vibrato-delay"]
+    pub vibrato_delay: FloatParam,
+    #[id = "vibrato-fade"]
+    pub vibrato_fade: FloatParam,
+    #[id = "vibrato-sync"]
+    pub vibrato_sync: BoolParam,
+    #[id = "vibrato-division"]
+    pub vibrato_division: EnumParam<VibratoDivision>,
+    #[id = "vibrato-mod-wheel"]
+    pub vibrato_mod_wheel: FloatParam,
+    #[id = "voice-mode"]
+    pub voice_mode: EnumParam<VoiceMode>,
+    #[id = "portamento-time"]
+    pub portamento_time: FloatParam,
+    #[id = "lyric-crossfade"]
+    pub lyric_crossfade: FloatParam,
+    #[id = "lyric-grace"]
+    pub lyric_grace: FloatParam,
+    #[id = "note-priority"]
+    pub note_priority: EnumParam<NotePriority>,
+    #[id = "overshoot"]
+    pub overshoot: FloatParam,
+    #[id = "overshoot-time"]
+    pub overshoot_time: FloatParam,
+    #[id = "preparation"]
+    pub preparation: FloatParam,
+    #[id = "preparation-time"]
+    pub preparation_time: FloatParam,
+    #[id = "drift"]
+    pub drift: FloatParam,
+    #[id = "drift-rate"]
+    pub drift_rate: FloatParam,
+    #[id = "attack"]
+    pub attack: FloatParam,
+    #[id = "decay"]
+    pub decay: FloatParam,
+    #[id = "sustain"]
+    pub sustain: FloatParam,
+    #[id = "release"]
+    pub release: FloatParam,
+    #[id = "consonant-velocity"]
+    pub consonant_velocity: FloatParam,
+    #[id = "consonant-velocity-sens"]
+    pub consonant_velocity_sens: FloatParam,
+    #[id = "sustain-mode"]
+    pub sustain_mode: EnumParam<SustainMode>,
+    #[id = "natural-tail"]
+    pub natural_tail: BoolParam,
+    #[id = "end-breath"]
+    pub end_breath: BoolParam,
+    #[id = "ustx-track"]
+    pub ustx_track: IntParam,
+    #[id = "record"]
+    pub record: BoolParam,
 }
 
 impl Default for PlutauParams {
@@ -137,14 +284,29 @@ impl Default for PlutauParams {
             .with_unit(" dB")
             .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
             .with_string_to_value(formatters::s2v_f32_gain_to_db()),
-            instant_cutoff: BoolParam::new("Instant Cutoff", true),
             lyric_settings: Arc::new(Mutex::new(LyricSettings::new())),
+            singer_flags: Arc::new(Mutex::new(String::from(""))),
             singer_dir: Mutex::new(String::from("")),
             singer: Arc::new(Mutex::new(String::from("None"))),
             cur_sample: Arc::new(Mutex::new(String::from(""))),
             lyrics: Arc::new(Mutex::new(String::from(""))),
             vowel: IntParam::new("Vowel", 0, IntRange::Linear { min: 0, max: 4 }),
-            consonant: IntParam::new("Consonant", 0, IntRange::Linear { min: 0, max: 14 }),
+            consonant: IntParam::new(
+                "Consonant",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: kana::BASIC_ROWS as i32 - 1,
+                },
+            ),
+            extended_consonant: IntParam::new(
+                "Extended Consonant",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: (kana::GRID.len() - kana::BASIC_ROWS) as i32,
+                },
+            ),
             oto: Mutex::new(Oto::new(String::from(""))),
             bend_range: FloatParam::new(
                 "Bend Range",
@@ -163,6 +325,222 @@ impl Default for PlutauParams {
             )
             .with_unit(" samples"),
             crossfade_on: BoolParam::new("Crossfade", true),
+            formant: FloatParam::new(
+                "Gender/Formant",
+                0.0,
+                FloatRange::Linear {
+                    min: -12.0,
+                    max: 12.0,
+                },
+            )
+            .with_unit(" semitones")
+            .with_step_size(0.1),
+            breathiness: FloatParam::new(
+                "Breathiness",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            breath_cc: IntParam::new("Breath CC", 2, IntRange::Linear { min: 0, max: 127 }),
+            vibrato_rate: FloatParam::new(
+                "Vibrato Rate",
+                5.5,
+                FloatRange::Linear {
+                    min: 0.1,
+                    max: 12.0,
+                },
+            )
+            .with_unit(" Hz")
+            .with_step_size(0.1),
+            vibrato_depth: FloatParam::new(
+                "Vibrato Depth",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 200.0,
+                },
+            )
+            .with_unit(" cents")
+            .with_step_size(1.0),
+            vibrato_delay: FloatParam::new(
+                "Vibrato Delay",
+                300.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+            vibrato_fade: FloatParam::new(
+                "Vibrato Fade-In",
+                200.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+            vibrato_sync: BoolParam::new("Vibrato Tempo Sync", false),
+            vibrato_division: EnumParam::new("Vibrato Division", VibratoDivision::EighthTriplet),
+            vibrato_mod_wheel: FloatParam::new(
+                "Mod Wheel Vibrato",
+                50.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 200.0,
+                },
+            )
+            .with_unit(" cents")
+            .with_step_size(1.0),
+            voice_mode: EnumParam::new("Voice Mode", VoiceMode::Legato),
+            portamento_time: FloatParam::new(
+                "Portamento Time",
+                80.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+            lyric_crossfade: FloatParam::new(
+                "Lyric Crossfade",
+                30.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 200.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+            lyric_grace: FloatParam::new(
+                "Lyric Grace Window",
+                5.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 20.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            note_priority: EnumParam::new("Note Priority", NotePriority::Last),
+            overshoot: FloatParam::new(
+                "Overshoot",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 100.0,
+                },
+            )
+            .with_unit(" cents")
+            .with_step_size(1.0),
+            overshoot_time: FloatParam::new(
+                "Overshoot Time",
+                120.0,
+                FloatRange::Linear {
+                    min: 10.0,
+                    max: 500.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+            preparation: FloatParam::new(
+                "Preparation Dip",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 100.0,
+                },
+            )
+            .with_unit(" cents")
+            .with_step_size(1.0),
+            preparation_time: FloatParam::new(
+                "Preparation Time",
+                80.0,
+                FloatRange::Linear {
+                    min: 10.0,
+                    max: 500.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+            drift: FloatParam::new(
+                "Pitch Drift",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 50.0,
+                },
+            )
+            .with_unit(" cents")
+            .with_step_size(1.0),
+            drift_rate: FloatParam::new(
+                "Drift Rate",
+                1.0,
+                FloatRange::Linear { min: 0.1, max: 5.0 },
+            )
+            .with_unit(" Hz")
+            .with_step_size(0.1),
+            attack: FloatParam::new(
+                "Attack",
+                5.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+            decay: FloatParam::new(
+                "Decay",
+                100.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+            sustain: FloatParam::new("Sustain", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            release: FloatParam::new(
+                "Release",
+                80.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2000.0,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(1.0),
+            consonant_velocity: FloatParam::new(
+                "Consonant Velocity",
+                100.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 200.0,
+                },
+            )
+            .with_step_size(1.0),
+            consonant_velocity_sens: FloatParam::new(
+                "Consonant Velocity Sens",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            sustain_mode: EnumParam::new("Sustain Mode", SustainMode::Loop),
+            natural_tail: BoolParam::new("Natural Tail", false),
+            end_breath: BoolParam::new("End Breath", false),
+            ustx_track: IntParam::new("USTX Track", 1, IntRange::Linear { min: 1, max: 16 }),
+            record: BoolParam::new("Record", false),
         }
     }
 }
@@ -173,7 +551,7 @@ impl Plugin for Plutau {
     const URL: &'static str = "https://avi86.bandcamp.com";
     const EMAIL: &'static str = "info@example.com";
     const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-    const SAMPLE_ACCURATE_AUTOMATION: bool = false;
+    const SAMPLE_ACCURATE_AUTOMATION: bool = true;
     const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
 
@@ -199,6 +577,7 @@ impl Plugin for Plutau {
             self.params.singer.clone(),
             self.params.cur_sample.clone(),
             self.params.lyrics.clone(),
+            self.params.singer_flags.clone(),
             self.params.editor_state.clone(),
             Arc::new(Mutex::new(producer)),
             Arc::clone(&self.visualizer),
@@ -214,6 +593,7 @@ impl Plugin for Plutau {
         nih_log!("changed sample rate to {}", buffer_config.sample_rate);
 
         self.sample_rate = buffer_config.sample_rate;
+        self.singer_flags = Flags::parse(&self.params.singer_flags.lock().unwrap());
         let singer =
             Path::new(self.params.singer_dir.lock().unwrap().clone().as_str()).to_path_buf();
 
@@ -237,214 +617,671 @@ impl Plugin for Plutau {
         }
 
         self.process_messages();
-        self.process_midi(context, buffer);
-
-        let mut amplitude = 0.0;
 
         if !context.transport().playing {
-            // reset lyric index, may cause desyncs if not playing from start of song
-            self.params.lyric_settings.lock().unwrap().lyric_file.index = 0;
+            // reset lyric index, only used until the file's lyric positions have been learned
+            let mut lyric_settings = self.params.lyric_settings.lock().unwrap();
+            lyric_settings.lyric_file.stop();
+            lyric_settings.lyric_midi.index = 0;
+            lyric_settings.lyric_ust.index = 0;
+            lyric_settings.lyric_ustx.index = 0;
         }
+        self.tempo = context.transport().tempo.unwrap_or(120.0);
+        self.song_position = match (context.transport().playing, context.transport().pos_beats()) {
+            (true, Some(beat)) => Some(beat),
+            _ => None,
+        };
+        let record = self.params.record.value();
+        if record && !self.was_recording {
+            self.recording = Recording::new();
+        }
+        self.was_recording = record;
 
-        for playing_sample in &mut self.playing_samples {
-            // attempt to get sample data
-            if let Some(loaded_sample) = self.loaded_samples.get(&playing_sample.handle) {
-                let source_frequency = self.sample_frequency as f32;
-                let target_frequency = self.midi_frequency as f32;
-                //--------------------------------
-
-                let sampling_frequency = self.sample_rate as f32;
-
-                let source_wavelength = sampling_frequency as f32 / source_frequency as f32;
-                let target_wavelength = sampling_frequency as f32 / target_frequency as f32;
-                let speed = Speed::from_f32(1.0);
-
-                // Left
-                let mut l_alternating_hann = AlternatingHann::new(source_wavelength);
-                let mut l_analysis = TdpsolaAnalysis::new(&l_alternating_hann);
-
-                let l_padding_length = source_wavelength as usize + 1;
-                for _ in 0..l_padding_length {
-                    l_analysis.push_sample(0.0, &mut l_alternating_hann);
-                }
-                let l_in: Vec<f32> = loaded_sample.samples[0].clone();
-
-                for sample in l_in.iter() {
-                    l_analysis.push_sample(*sample, &mut l_alternating_hann);
-                }
-
-                let mut l_synthesis = TdpsolaSynthesis::new(speed, target_wavelength);
-                let mut l_out: Vec<f32> = Vec::new();
-                for output_sample in l_synthesis.iter(&l_analysis).skip(l_padding_length) {
-                    l_out.push(output_sample);
-                }
+        // render up to each event so notes start and stop on the exact sample
+        let tempo = context.transport().tempo;
+        let mut amplitude = 0.0;
+        let mut segment_start = 0;
+        for event in self.block_events(context) {
+            let timing = (event.timing() as usize).min(buffer.samples());
+            if timing > segment_start {
+                amplitude += self.render_voices(buffer, segment_start, timing, tempo);
+                segment_start = timing;
+            }
+            self.process_event(event);
+        }
+        amplitude += self.render_voices(buffer, segment_start, buffer.samples(), tempo);
 
-                // Right
-                let mut r_alternating_hann = AlternatingHann::new(source_wavelength);
-                let mut r_analysis = TdpsolaAnalysis::new(&r_alternating_hann);
+        amplitude /= buffer.samples() as f32 * buffer.channels() as f32;
+        self.visualizer.store(amplitude);
 
-                let r_padding_length = source_wavelength as usize + 1;
-                for _ in 0..r_padding_length {
-                    r_analysis.push_sample(0.0, &mut r_alternating_hann);
+        // remove samples that are done playing
+        self.playing_samples
+            .retain(|e| match self.loaded_samples.get(&e.handle) {
+                Some(sample) => {
+                    e.position < e.length(sample.samples[0].len())
+                        && !e.is_faded_out()
+                        && e.state != PlayingState::DONE
                 }
-                let r_in: Vec<f32> = loaded_sample.samples[1].clone();
+                None => false,
+            });
+        self.sample_clock += buffer.samples() as u64;
+        let grace = self.lyric_grace();
+        self.late_lyric.expire(self.sample_clock, grace);
 
-                for sample in r_in.iter() {
-                    r_analysis.push_sample(*sample, &mut r_alternating_hann);
-                }
+        ProcessStatus::Normal
+    }
+}
 
-                let mut r_synthesis = TdpsolaSynthesis::new(speed, target_wavelength);
-                let mut r_out: Vec<f32> = Vec::new();
-                for output_sample in r_synthesis.iter(&r_analysis).skip(r_padding_length) {
-                    r_out.push(output_sample);
-                }
+impl Plutau {
+    /// Renders the voices into the samples of `buffer` from `start` to `end`, returning the summed amplitude.
+    /// Pitch and modulation are updated once per call. Each voice is only pitch shifted when its note changes,
+    /// modulation plays the shifted sample back faster or slower so the audio thread doesn't re-render it.
+    fn render_voices(
+        &mut self,
+        buffer: &mut Buffer,
+        start: usize,
+        end: usize,
+        tempo: Option<f64>,
+    ) -> f32 {
+        let mut amplitude = 0.0;
+        let vibrato_settings = self.vibrato_settings(tempo);
+        let pitch_curve_settings = self.pitch_curve_settings();
+        let adsr_settings = self.adsr_settings();
+        let crossfade_length = self.params.crossfade_length.value() as u32;
+        let played = (end - start) as isize;
 
-                let shifted_sample: LoadedSample = LoadedSample {
-                    samples: vec![l_out.clone(), r_out.clone()],
-                    frequency: target_frequency,
+        for playing_sample in &mut self.playing_samples {
+            // attempt to get sample data
+            if let Some(loaded_sample) = self.loaded_samples.get(&playing_sample.handle) {
+                let vibrato = playing_sample.vibrato.cents(&vibrato_settings);
+                playing_sample
+                    .vibrato
+                    .advance(played as f32 / self.sample_rate, &vibrato_settings);
+
+                // an imported pitch bend replaces the automatic pitch curve
+                let expression = &playing_sample.expression;
+                let mut time = playing_sample.age * 1000.0;
+                let curve = match &expression.pitch_bend {
+                    Some(bend) => bend.cents(time),
+                    None => playing_sample.pitch_curve.cents(&pitch_curve_settings),
+                } + expression
+                    .pitch_deviation
+                    .as_ref()
+                    .map_or(0.0, |deviation| deviation.cents(time));
+                let pitch = playing_sample.glide.current() + curve / 100.0;
+                playing_sample.age += played as f32 / self.sample_rate;
+                playing_sample
+                    .glide
+                    .advance(played as f32 / self.sample_rate);
+                playing_sample
+                    .pitch_curve
+                    .advance(played as f32 / self.sample_rate, &pitch_curve_settings);
+
+                let offset = playing_sample.flags.pitch_offset_semitones();
+                let target_frequency =
+                    midi_to_hz(pitch + self.pitch_bend + offset + vibrato / 100.0);
+                // rendered at the note the voice is on, or gliding to
+                let note_frequency = midi_to_hz(playing_sample.glide.target() + offset);
+                // the g flag and the formant param stack
+                let formant_shift =
+                    playing_sample.flags.formant_shift() + self.params.formant.value();
+                let shifted_sample = match playing_sample.rendered.take() {
+                    Some(rendered) if !rendered.is_stale(note_frequency, formant_shift) => rendered,
+                    _ => synth::render(
+                        loaded_sample,
+                        note_frequency,
+                        formant_shift,
+                        &playing_sample.flags,
+                        playing_sample.stretch.as_ref(),
+                        (playing_sample.sustain_mode == SustainMode::Freeze)
+                            .then_some(playing_sample.sustain_loop.start as usize),
+                        self.sample_rate,
+                    ),
                 };
+                let rate = target_frequency / shifted_sample.target_frequency;
+                // the B flag, the breathiness param and the breath CC stack
+                let breath_mix = (playing_sample.flags.breath_mix()
+                    + self.params.breathiness.value()
+                    + self.breath_cc_value)
+                    .min(1.0);
+
+                // the loop has to hold whole periods of the rendered pitch to stay in phase
+                let wavelength = self.sample_rate / shifted_sample.target_frequency;
+                let loop_start = playing_sample.sustain_loop.start as isize;
+                let loop_end = (playing_sample.sustain_loop.end_for_wavelength(wavelength)
+                    as isize)
+                    .min(playing_sample.length(loaded_sample.samples[0].len()));
+                let loop_length = loop_end - loop_start;
+                // the crossfade reads the samples leading up to the loop start
+                let loop_crossfade = (crossfade_length as isize)
+                    .min(loop_length)
+                    .min(loop_start)
+                    .max(1);
+
                 // channel_samples is [a, b, c]
-                for channel_samples in buffer.iter_samples() {
+                for channel_samples in buffer.iter_samples().skip(start).take(end - start) {
                     // if sample isnt in the future
                     if playing_sample.position >= 0 {
+                        let envelope = playing_sample
+                            .envelope
+                            .next(&adsr_settings, self.sample_rate);
+                        // the imported note's own envelope and intensity stack with the ADSR
+                        let gain = playing_sample.gain
+                            * playing_sample.fade
+                            * envelope
+                            * playing_sample.expression.gain(time);
+                        let looping = self.params.crossfade_on.value()
+                            && playing_sample.sustain_mode == SustainMode::Loop
+                            && !playing_sample.ignore_fade
+                            && loop_length > 0
+                            && playing_sample.position >= loop_end - loop_crossfade;
+                        // a time-stretch crossfades into a repeat of the period before
+                        let repeat_gain = playing_sample
+                            .time_stretch
+                            .map_or(0.0, |stretch| stretch.repeat_gain(wavelength));
+                        let repeat = playing_sample.behind(wavelength);
+                        // a freeze crossfades into its frozen frame over the first period
+                        let frozen = playing_sample
+                            .frozen
+                            .map(|cycles| (cycles.fract(), cycles.min(1.0)));
+                        // interpolate towards the next sample in the playing direction
+                        let direction = if playing_sample.reverse { -1 } else { 1 };
+                        let phase = playing_sample.phase;
+                        let read = |channel: usize, position: isize, phase: f32| {
+                            let a =
+                                shifted_sample.get(channel, position.max(0) as usize, breath_mix);
+                            let b = shifted_sample.get(
+                                channel,
+                                (position + direction).max(0) as usize,
+                                breath_mix,
+                            );
+                            a + (b - a) * phase
+                        };
                         for (channel_index, sample) in channel_samples.into_iter().enumerate() {
-                            let s = shifted_sample
-                                .samples
-                                .get(channel_index)
-                                .unwrap_or(&vec![])
-                                .get(playing_sample.position as usize)
-                                .unwrap_or(&0.0)
-                                * playing_sample.gain;
-
-                            if self.params.crossfade_on.value() {
-                                if playing_sample.ignore_fade {
-                                    *sample += s;
-                                    amplitude += s.abs();
-                                } else {
-                                    nih_log!(
-                                        "pos: {}, start: {}, end: {}, crossfade start: {}",
-                                        playing_sample.position,
-                                        playing_sample.vowel_start,
-                                        playing_sample.vowel_end,
-                                        playing_sample.vowel_end
-                                            - self.params.crossfade_length.value() as u32
-                                    );
-                                    // If crossfade has started, average samples from current point and from the loop start with offset
-                                    if playing_sample.position
-                                        >= (playing_sample.vowel_end
-                                            - self.params.crossfade_length.value() as u32)
-                                            as isize
-                                    {
-                                        let offset = playing_sample.position
-                                            - (playing_sample.vowel_end
-                                                - self.params.crossfade_length.value() as u32)
-                                                as isize;
-                                        nih_log!(
-                                            "crossfade offset: {}, new sample pos: {}",
-                                            offset,
-                                            playing_sample.vowel_start as isize + offset as isize
-                                        );
-                                        let s2 = shifted_sample
-                                            .samples
-                                            .get(channel_index)
-                                            .unwrap_or(&vec![])
-                                            .get(
-                                                playing_sample.vowel_start as usize
-                                                    + offset as usize,
-                                            )
-                                            .unwrap_or(&0.0)
-                                            * playing_sample.gain;
-                                        nih_log!("s: {}, s2: {}", s, s2);
-                                        let ratio = offset as f32
-                                            / self.params.crossfade_length.value() as f32;
-                                        nih_log!(
-                                            "with ratio {}: s: {}, s2: {}",
-                                            ratio,
-                                            s * (1.0 - ratio),
-                                            s2 * ratio
-                                        );
-                                        *sample +=
-                                            s * (1.0 - ratio) + s2 * ratio * playing_sample.gain;
-                                        amplitude += (s.abs() * (1.0 - ratio))
-                                            + (s2.abs() * ratio) * playing_sample.gain;
-                                    } else {
-                                        *sample += s;
-                                        amplitude += s.abs();
-                                    }
-                                }
-                            } else {
-                                *sample += s;
-                                amplitude += s.abs();
+                            let mut s = read(channel_index, playing_sample.position, phase);
+                            if repeat_gain > 0.0 {
+                                let r = read(channel_index, repeat.0, repeat.1);
+                                s = s * (1.0 - repeat_gain) + r * repeat_gain;
+                            }
+                            if let Some((cycle, fade)) = frozen {
+                                let f = shifted_sample.get_frozen(channel_index, cycle, breath_mix);
+                                s = s * (1.0 - fade) + f * fade;
+                            }
+                            s *= gain;
+                            // crossfade the end of the loop into the samples leading up to its start
+                            if looping {
+                                let offset = playing_sample.position - (loop_end - loop_crossfade);
+                                let (fade_out, fade_in) =
+                                    equal_power(offset as f32 / loop_crossfade as f32);
+                                let s2 = read(
+                                    channel_index,
+                                    playing_sample.position - loop_length,
+                                    phase,
+                                ) * gain;
+                                s = s * fade_out + s2 * fade_in;
                             }
+                            // crossfade from the loop into the recorded tail
+                            if let Some(tail) = playing_sample.tail {
+                                let t = read(channel_index, tail, phase) * gain;
+                                s = s * (1.0 - playing_sample.tail_fade)
+                                    + t * playing_sample.tail_fade;
+                            }
+
+                            *sample += s;
+                            amplitude += s.abs();
                         }
+                        playing_sample.advance_fade();
+                    }
+                    let step = playing_sample.step(rate);
+                    playing_sample.advance_tail(crossfade_length, step);
+                    let held = playing_sample.state == PlayingState::SUSTAIN;
+                    if let Some(stretch) = &mut playing_sample.time_stretch {
+                        if stretch.advance(rate, wavelength, held) {
+                            playing_sample.jump_back(wavelength);
+                        }
+                    }
+                    // once a freeze has faded in, playback stays on the frozen frame
+                    let holding = playing_sample.frozen.is_some_and(|cycles| cycles >= 1.0);
+                    if let Some(cycles) = &mut playing_sample.frozen {
+                        *cycles += rate / wavelength;
+                        if *cycles >= 2.0 {
+                            *cycles -= 1.0;
+                        }
+                    }
+                    if !holding {
+                        playing_sample.advance_position(step);
                     }
-                    playing_sample.position += 1;
+                    time += 1000.0 / self.sample_rate;
 
                     match playing_sample.state {
                         PlayingState::ATTACK => {
                             if playing_sample.position >= playing_sample.vowel_start as isize {
                                 playing_sample.state = PlayingState::SUSTAIN;
+                                playing_sample.ignore_fade = false;
+                                if playing_sample.sustain_mode == SustainMode::Stretch {
+                                    let length =
+                                        playing_sample.vowel_end as isize - playing_sample.position;
+                                    playing_sample.time_stretch =
+                                        Some(TimeStretch::new(length as f32));
+                                }
                             }
                         }
-                        PlayingState::SUSTAIN => {
-                            if playing_sample.position > playing_sample.vowel_end as isize {
-                                playing_sample.position = (playing_sample.vowel_start
-                                    + self.params.crossfade_length.value() as u32)
-                                    as isize;
-                            }
-                            if playing_sample.position
-                                > (playing_sample.vowel_start
-                                    + self.params.crossfade_length.value() as u32)
-                                    as isize
+                        PlayingState::SUSTAIN
+                            if playing_sample.sustain_mode == SustainMode::Freeze =>
+                        {
+                            if playing_sample.frozen.is_none()
+                                && playing_sample.position >= loop_start
                             {
-                                playing_sample.ignore_fade = false;
+                                let (position, phase) =
+                                    (playing_sample.position, playing_sample.phase);
+                                let played = (position - loop_start) as f32 + phase;
+                                playing_sample.frozen = Some(played / wavelength);
+                            }
+                        }
+                        PlayingState::SUSTAIN if loop_length > 2 => {
+                            match playing_sample.sustain_mode {
+                                SustainMode::PingPong => {
+                                    // turn around at either end without playing the end sample twice
+                                    if playing_sample.reverse
+                                        && playing_sample.position < loop_start
+                                    {
+                                        playing_sample.reverse = false;
+                                        playing_sample.position = loop_start + 1;
+                                    } else if !playing_sample.reverse
+                                        && playing_sample.position >= loop_end
+                                    {
+                                        playing_sample.reverse = true;
+                                        playing_sample.position = loop_end - 2;
+                                    }
+                                }
+                                SustainMode::Loop => {
+                                    if playing_sample.position >= loop_end {
+                                        playing_sample.position -= loop_length;
+                                    }
+                                }
+                                _ => {}
                             }
                         }
                         PlayingState::RELEASE => {
-                            playing_sample.ignore_fade = true;
-                            playing_sample.position = playing_sample.vowel_end as isize;
-                            playing_sample.state = PlayingState::DONE;
+                            if playing_sample.envelope.is_done() {
+                                playing_sample.state = PlayingState::DONE;
+                            }
                         }
                         _ => {}
                     }
                 }
+                playing_sample.rendered = Some(shifted_sample);
             }
         }
 
-        amplitude /= buffer.samples() as f32 * buffer.channels() as f32;
-        self.visualizer.store(amplitude);
+        amplitude
+    }
 
-        // remove samples that are done playing
-        self.playing_samples
-            .retain(|e| match self.loaded_samples.get(&e.handle) {
-                Some(sample) => e.position < sample.samples[0].len() as isize,
-                None => false,
-            });
-        if self.params.instant_cutoff.value() {
-            self.playing_samples
-                .retain(|e| match self.loaded_samples.get(&e.handle) {
-                    Some(_sample) => e.state != PlayingState::DONE,
-                    None => false,
-                });
+    fn velocity_to_gain(&self, velocity: u8) -> f32 {
+        let max_vol = self.params.gain.value();
+        // this is just mapping from the velocity range to volume range
+        max_vol * (velocity as f32 / 127.0)
+    }
+
+    /// Resolves the current lyric and sets up a voice for it, `None` if there is no sample for it
+    fn start_voice(&mut self, note: u8, velocity: f32) -> Option<PlayingSample> {
+        // update lyric if not using sysex
+        // the extended rows come after the basic ones in the grid
+        let consonant = match self.params.extended_consonant.value() {
+            0 => self.params.consonant.value(),
+            row => kana::BASIC_ROWS as i32 - 1 + row,
+        };
+        self.params
+            .lyric_settings
+            .lock()
+            .unwrap()
+            .lyric_param
+            .current = Phoneme::new(self.params.vowel.value() as u8, consonant as u8);
+
+        self.params.lyric_settings.lock().unwrap().lyric_ustx.track =
+            self.params.ustx_track.value() as usize - 1;
+
+        nih_log!(
+            "source: {:?}",
+            self.params.lyric_settings.lock().unwrap().lyric_source
+        );
+
+        self.lyric = self.params.lyric_settings.lock().unwrap().get_jpn_utf8();
+        // per-note flags override the singer's flags
+        let flags = self
+            .singer_flags
+            .merge(&self.params.lyric_settings.lock().unwrap().get_flags());
+        let expression = self.params.lyric_settings.lock().unwrap().get_expression();
+        // the note has taken its lyric, an NSX-1 message moves on to its next syllable
+        self.params.lyric_settings.lock().unwrap().advance();
+
+        // phoneme will be the path to the phoneme wav file
+        let phoneme = format!(
+            "{}{}{}.wav",
+            self.params.singer_dir.lock().unwrap().clone(),
+            std::path::MAIN_SEPARATOR_STR,
+            self.lyric.clone()
+        );
+        nih_log!("playing phoneme: {}", phoneme);
+        *self.params.cur_sample.lock().unwrap() = phoneme.clone();
+
+        let entry = {
+            let oto = self.params.oto.lock().unwrap();
+            // lyrics are written in the bank's script first, so any lyric source works with any CV bank
+            [oto.script.convert(&self.lyric), self.lyric.clone()]
+                .into_iter()
+                .filter(|lyric| !lyric.is_empty())
+                .find_map(|lyric| {
+                    oto.get_alias(&lyric)
+                        .or_else(|| oto.get_entry(lyric + ".wav"))
+                })?
+                .clone()
+        };
+        let mut playing_sample = self.new_voice(
+            &entry,
+            self.velocity_to_gain((velocity * 127.0) as u8),
+            expression
+                .velocity
+                .unwrap_or_else(|| self.consonant_velocity(velocity)),
+        )?;
+
+        playing_sample.state = PlayingState::ATTACK;
+        playing_sample.flags = flags;
+        playing_sample.note = note;
+        playing_sample.glide = Glide::new(note as f32);
+        playing_sample.pitch_curve = PitchCurve::new(note as u32 + 1);
+        playing_sample.expression = expression;
+
+        Some(playing_sample)
+    }
+
+    /// Sets up a voice for an oto entry, `None` if its sample isn't loaded
+    fn new_voice(
+        &mut self,
+        entry: &OtoEntry,
+        gain: f32,
+        consonant_velocity: f32,
+    ) -> Option<PlayingSample> {
+        let singer_dir = PathBuf::from(self.params.singer_dir.lock().unwrap().clone());
+        // None if no samples are loaded
+        let (path, sample_data) = self
+            .loaded_samples
+            .get_key_value(&entry.file_path(&singer_dir))?;
+        self.sample_frequency = sample_data.frequency;
+        nih_log!("sample length in samples: {}", sample_data.samples[0].len());
+
+        let mut playing_sample = PlayingSample::new(path.clone(), gain);
+
+        (playing_sample.vowel_start, playing_sample.vowel_end) =
+            self.vowel_region(entry, sample_data.samples[0].len());
+        let whole_vowel = LoopPoints {
+            start: playing_sample.vowel_start,
+            end: playing_sample.vowel_end.max(playing_sample.vowel_start),
+        };
+        let loops = sample_data.loops.get(&entry.alias);
+
+        playing_sample.sustain_mode = self.params.sustain_mode.value();
+        playing_sample.sustain_loop = match playing_sample.sustain_mode {
+            SustainMode::Freeze => loops.map(|loops| loops.freeze),
+            _ => loops.map(|loops| loops.sustain),
+        }
+        .unwrap_or(whole_vowel);
+
+        let offset = ((entry.offset as f32 / 1000.0) * self.sample_rate) as u32;
+        let stretch = StretchRegion {
+            start: offset.min(whole_vowel.start),
+            end: whole_vowel.start,
+            factor: consonant_factor(consonant_velocity),
+        };
+        if stretch.factor != 1.0 {
+            playing_sample.vowel_start = stretch.map(playing_sample.vowel_start);
+            playing_sample.vowel_end = stretch.map(playing_sample.vowel_end);
+            playing_sample.sustain_loop = LoopPoints {
+                start: stretch.map(playing_sample.sustain_loop.start),
+                end: stretch.map(playing_sample.sustain_loop.end),
+            };
+            playing_sample.stretch = Some(stretch);
+        }
+
+        Some(playing_sample)
+    }
+
+    /// UTAU consonant velocity for a note, the param plus the note velocity scaled by the sensitivity
+    fn consonant_velocity(&self, velocity: f32) -> f32 {
+        let from_velocity = (velocity - 0.5) * 200.0 * self.params.consonant_velocity_sens.value();
+        (self.params.consonant_velocity.value() + from_velocity).clamp(0.0, 200.0)
+    }
+
+    /// Start and end of the vowel in samples, from the oto consonant and cutoff markers
+    fn vowel_region(&self, entry: &OtoEntry, length: usize) -> (u32, u32) {
+        let offset = (entry.offset as f32 / 1000.0) * self.sample_rate;
+        let vowel_start = (((entry.consonant as f32 / 1000.0) * self.sample_rate) + offset) as u32;
+        let vowel_end =
+            (length as f32 - ((entry.cutoff as f32 / 1000.0) * self.sample_rate)) as u32;
+        (vowel_start, vowel_end)
+    }
+
+    /// Sets up a voice for the end breath alias of the current lyric, like `a R` or `息`
+    fn end_breath_voice(&mut self) -> Option<PlayingSample> {
+        let vowel = kana::last_vowel(&self.lyric);
+        let entry = {
+            let oto = self.params.oto.lock().unwrap();
+            let mut aliases = vec![];
+            if let Some(vowel) = vowel {
+                aliases.push(format!("{} R", vowel));
+                aliases.push(format!("{}R", vowel));
+            }
+            aliases.push(String::from("息"));
+            aliases
+                .iter()
+                .find_map(|alias| oto.get_alias(alias))
+                .cloned()?
+        };
+        nih_log!(
+            "playing end breath: {}",
+            String::from_utf8_lossy(&entry.alias)
+        );
+        self.new_voice(
+            &entry,
+            self.params.gain.value(),
+            self.params.consonant_velocity.value(),
+        )
+    }
+
+    /// Plays a note, returning the lyric it sings.
+    /// `None` when a held note with a higher priority keeps sounding instead.
+    fn note_on(&mut self, note: u8, velocity: f32) -> Option<String> {
+        self.note_stack.push(note);
+        // a held note with a higher priority keeps sounding
+        if self.note_stack.top(self.params.note_priority.value()) != Some(note) {
+            return None;
+        }
+        self.note = note;
+        nih_log!("playing note: {}", note);
+
+        let voice = self.start_voice(note, velocity);
+        let held = self.playing_samples.iter().position(|e| e.is_active());
+
+        match held {
+            Some(index) if self.params.voice_mode.value() == VoiceMode::Legato => {
+                let crossfade = self.params.lyric_crossfade.value() / 1000.0 * self.sample_rate;
+
+                self.glide_held_voice(note);
+                let previous = &mut self.playing_samples[index];
+
+                // without a sample for the new lyric the held voice just glides,
+                // as it does when the lyric doesn't change
+                if let Some(mut voice) = voice.filter(|voice| voice.handle != previous.handle) {
+                    voice.glide = previous.glide;
+                    voice.vibrato = previous.vibrato;
+                    voice.pitch_curve = previous.pitch_curve;
+                    voice.fade_in(crossfade);
+                    previous.fade_out(crossfade);
+                    self.playing_samples.push(voice);
+                }
+            }
+            _ => {
+                self.release_held_voices(false);
+                if let Some(voice) = voice {
+                    self.playing_samples.push(voice);
+                }
+            }
+        }
+        Some(self.lyric.clone())
+    }
+
+    fn note_off(&mut self, note: u8) {
+        let priority = self.params.note_priority.value();
+        let sounding = self.note_stack.top(priority);
+        self.note_stack.remove(note);
+
+        if sounding != Some(note) {
+            return;
+        }
+        match self.note_stack.top(priority) {
+            // fall back to the note that is still held, like a mono synth
+            Some(fallback) => {
+                self.note = fallback;
+                self.glide_held_voice(fallback);
+            }
+            None => self.release_held_voices(self.params.end_breath.value()),
+        }
+    }
+
+    /// Switches the last note to a lyric which arrived within the grace window after it,
+    /// for hosts which send the lyric just after the note instead of before it
+    fn switch_late_lyric(&mut self, time: u64) {
+        if !matches!(
+            self.params.lyric_settings.lock().unwrap().lyric_source,
+            LyricSource::SysEx
+        ) {
+            return;
+        }
+        let grace = self.lyric_grace();
+        let Some((note, velocity)) = self.late_lyric.take(time, grace) else {
+            return;
+        };
+        // only a voice still on its consonant can switch without being heard
+        let Some(index) = self
+            .playing_samples
+            .iter()
+            .position(|e| e.is_active() && e.state == PlayingState::ATTACK && e.note == note)
+        else {
+            return;
+        };
+        let Some(mut voice) = self.start_voice(note, velocity) else {
+            return;
+        };
+        if self.params.record.value() {
+            self.recording.set_lyric(note, &self.lyric);
+        }
+
+        let previous = &mut self.playing_samples[index];
+        if voice.handle == previous.handle {
+            return;
+        }
+        nih_log!("switching to late lyric: {}", self.lyric);
+        voice.gain = previous.gain;
+        voice.glide = previous.glide;
+        voice.vibrato = previous.vibrato;
+        voice.pitch_curve = previous.pitch_curve;
+        if previous.position > 0 {
+            // the old consonant has started, so crossfade rather than cut it off
+            let crossfade = self.params.lyric_crossfade.value() / 1000.0 * self.sample_rate;
+            previous.fade_out(crossfade);
+            voice.fade_in(crossfade);
         } else {
-            for playing_sample in &mut self.playing_samples {
-                if playing_sample.state == PlayingState::DONE
-                    && playing_sample.position < playing_sample.vowel_end as isize
-                {
-                    playing_sample.position = playing_sample.vowel_end as isize;
+            self.playing_samples.remove(index);
+        }
+        self.playing_samples.push(voice);
+    }
+
+    /// Length of the lyric grace window in samples
+    fn lyric_grace(&self) -> u64 {
+        (self.params.lyric_grace.value() / 1000.0 * self.sample_rate) as u64
+    }
+
+    /// Releases the held voice, optionally handing over to an end breath at the end of a phrase
+    fn release_held_voices(&mut self, end_breath: bool) {
+        let natural_tail = self.params.natural_tail.value();
+        let crossfade = self.params.lyric_crossfade.value() / 1000.0 * self.sample_rate;
+        let mut end_voice = if end_breath {
+            self.end_breath_voice()
+        } else {
+            None
+        };
+
+        for voice in self.playing_samples.iter_mut().filter(|e| e.is_active()) {
+            match &mut end_voice {
+                Some(end_voice) => {
+                    // the end breath takes over from the tail
+                    voice.release(false, self.sample_rate);
+                    voice.fade_out(crossfade);
+
+                    end_voice.note = voice.note;
+                    end_voice.flags = voice.flags;
+                    end_voice.glide = voice.glide;
+                    end_voice.vibrato = voice.vibrato;
+                    end_voice.pitch_curve = voice.pitch_curve;
                 }
+                None => voice.release(natural_tail, self.sample_rate),
             }
         }
 
-        ProcessStatus::Normal
+        if let Some(mut end_voice) = end_voice {
+            // play the end breath through once instead of looping it
+            end_voice.state = PlayingState::RELEASE;
+            end_voice.fade_in(crossfade);
+            self.playing_samples.push(end_voice);
+        }
     }
-}
 
-impl Plutau {
-    fn velocity_to_gain(&self, velocity: u8) -> f32 {
-        let max_vol = self.params.gain.value();
-        // this is just mapping from the velocity range to volume range
-        max_vol * (velocity as f32 / 127.0)
+    /// Glides the voice that is still held to `note` over the portamento time
+    fn glide_held_voice(&mut self, note: u8) {
+        let glide_time = self.params.portamento_time.value() / 1000.0;
+        if let Some(voice) = self.playing_samples.iter_mut().find(|e| e.is_active()) {
+            voice.note = note;
+            voice
+                .pitch_curve
+                .transition(note as f32 - voice.glide.target(), glide_time);
+            voice.glide.glide_to(note as f32, glide_time);
+        }
+    }
+
+    fn adsr_settings(&self) -> AdsrSettings {
+        AdsrSettings {
+            attack: self.params.attack.value() / 1000.0,
+            decay: self.params.decay.value() / 1000.0,
+            sustain: self.params.sustain.value(),
+            release: self.params.release.value() / 1000.0,
+        }
+    }
+
+    fn pitch_curve_settings(&self) -> PitchCurveSettings {
+        PitchCurveSettings {
+            overshoot: self.params.overshoot.value(),
+            overshoot_time: self.params.overshoot_time.value() / 1000.0,
+            preparation: self.params.preparation.value(),
+            preparation_time: self.params.preparation_time.value() / 1000.0,
+            drift: self.params.drift.value(),
+            drift_rate: self.params.drift_rate.value(),
+        }
+    }
+
+    fn vibrato_settings(&self, tempo: Option<f64>) -> VibratoSettings {
+        let rate = match tempo {
+            Some(tempo) if self.params.vibrato_sync.value() => {
+                self.params.vibrato_division.value().rate(tempo as f32)
+            }
+            _ => self.params.vibrato_rate.value(),
+        };
+        VibratoSettings {
+            rate,
+            depth: self.params.vibrato_depth.value()
+                + self.mod_wheel * self.params.vibrato_mod_wheel.value(),
+            delay: self.params.vibrato_delay.value() / 1000.0,
+            fade_in: self.params.vibrato_fade.value() / 1000.0,
+        }
     }
 
     fn process_messages(&mut self) {
@@ -468,6 +1305,9 @@ impl Plutau {
                             0 => LyricSource::Param,
                             1 => LyricSource::File,
                             2 => LyricSource::SysEx,
+                            3 => LyricSource::Midi,
+                            4 => LyricSource::Ust,
+                            5 => LyricSource::Ustx,
                             _ => LyricSource::Param,
                         };
                         self.params
@@ -477,6 +1317,14 @@ impl Plutau {
                             .set_lyric_source(source.clone());
                         nih_log!("Set lyric source to {:?}", source);
                     }
+                    ThreadMessage::SetSingerFlags(flags) => {
+                        self.singer_flags = Flags::parse(&flags);
+                        nih_log!("Set singer flags to {}", self.singer_flags);
+                        *self.params.singer_flags.lock().unwrap() = flags;
+                    }
+                    ThreadMessage::ExportRecording(path) => {
+                        self.export_recording(path.clone());
+                    }
                 }
             }
         }
@@ -484,159 +1332,116 @@ impl Plutau {
         self.consumer.replace(consumer);
     }
 
-    fn process_midi(&mut self, context: &mut impl ProcessContext<Self>, buffer: &mut Buffer) {
-        let mut next_event = context.next_event();
+    /// Collects the block's events, ordered so that lyrics and controllers at the same timing
+    /// as a note are applied before it, whatever order the host sent them in
+    fn block_events(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+    ) -> Vec<PluginNoteEvent<Self>> {
+        let mut events = vec![];
+        while let Some(event) = context.next_event() {
+            events.push(event);
+        }
+        events.sort_by_key(|event| {
+            let order = match event {
+                NoteEvent::NoteOn { .. } => 2,
+                NoteEvent::NoteOff { .. } => 1,
+                _ => 0,
+            };
+            (event.timing(), order)
+        });
+        events
+    }
 
-        for (sample_id, _channel_samples) in buffer.iter_samples().enumerate() {
-            while let Some(event) = next_event {
-                if event.timing() > sample_id as u32 {
-                    break;
+    fn process_event(&mut self, event: PluginNoteEvent<Self>) {
+        let time = self.sample_clock + event.timing() as u64;
+        let beat = self.beat_at(event.timing());
+        let record = self.params.record.value();
+        match event {
+            NoteEvent::NoteOn { note, velocity, .. } => {
+                self.params
+                    .lyric_settings
+                    .lock()
+                    .unwrap()
+                    .set_position(self.song_position.map(|_| beat));
+                let lyric = self.note_on(note, velocity);
+                self.late_lyric.note_on(time, note, velocity);
+                // a note which doesn't sound isn't recorded, the held note it's under carries on
+                if let Some(lyric) = lyric.filter(|_| record) {
+                    let pitch_bend = if self.pitch_bend != 0.0 {
+                        vec![(0.0, self.pitch_bend * 100.0)]
+                    } else {
+                        vec![]
+                    };
+                    let recorded = RecordedNote {
+                        beat,
+                        length: None,
+                        note,
+                        velocity,
+                        consonant_velocity: self.consonant_velocity(velocity),
+                        lyric,
+                        pitch_bend,
+                    };
+                    self.recording
+                        .note_on(recorded, self.song_position.is_some(), self.tempo);
                 }
-                match event {
-                    NoteEvent::NoteOn { note, velocity, .. } => {
-                        self.note = note;
-                        self.midi_frequency = midi_to_hz(note as f32 + self.pitch_bend);
-                        if !(self.playing_samples.is_empty()) {
-                            if !(self.playing_samples[0].state == PlayingState::DONE
-                                || self.playing_samples[0].state == PlayingState::RELEASE)
-                            {
-                                return;
-                            }
-                        }
-                        nih_log!("playing note: {}", note);
-
-                        // update lyric if not using sysex
-                        self.params
-                            .lyric_settings
-                            .lock()
-                            .unwrap()
-                            .lyric_param
-                            .current = Phoneme::new(
-                            self.params.vowel.value() as u8,
-                            self.params.consonant.value() as u8,
-                        );
-
-                        nih_log!(
-                            "source: {:?}",
-                            self.params.lyric_settings.lock().unwrap().lyric_source
-                        );
-
-                        self.lyric = self.params.lyric_settings.lock().unwrap().get_jpn_utf8();
-
-                        // phoneme will be the path to the phoneme wav file
-                        let phoneme = format!(
-                            "{}{}{}.wav",
-                            self.params.singer_dir.lock().unwrap().clone(),
-                            std::path::MAIN_SEPARATOR_STR,
-                            self.lyric.clone()
-                        );
-                        nih_log!("playing phoneme: {}", phoneme);
-                        *self.params.cur_sample.lock().unwrap() = phoneme.clone();
-                        // None if no samples are loaded
-                        if let Some((path, sample_data)) =
-                            self.loaded_samples.get_key_value(Path::new(&phoneme))
-                        {
-                            self.sample_frequency = sample_data.frequency;
-                            let offset = (self
-                                .params
-                                .oto
-                                .lock()
-                                .unwrap()
-                                .get_entry(self.lyric.clone() + ".wav")
-                                .unwrap()
-                                .offset as f32
-                                / 1000.0)
-                                * self.sample_rate;
-
-                            nih_log!("sample length in samples: {}", sample_data.samples[0].len());
-
-                            let mut playing_sample = PlayingSample::new(
-                                path.clone(),
-                                self.velocity_to_gain((velocity * 127.0) as u8),
-                            );
-
-                            playing_sample.vowel_start =
-                                (((self
-                                    .params
-                                    .oto
-                                    .lock()
-                                    .unwrap()
-                                    .get_entry(self.lyric.clone() + ".wav")
-                                    .unwrap()
-                                    .consonant as f32
-                                    / 1000.0)
-                                    * self.sample_rate)
-                                    + offset) as u32;
-                            playing_sample.vowel_end = (sample_data.samples[0].len() as f32
-                                - ((self
-                                    .params
-                                    .oto
-                                    .lock()
-                                    .unwrap()
-                                    .get_entry(self.lyric.clone() + ".wav")
-                                    .unwrap()
-                                    .cutoff as f32
-                                    / 1000.0)
-                                    * self.sample_rate))
-                                as u32;
-
-                            // start at correct position in buffer
-                            playing_sample.position = -(event.timing() as isize);
-
-                            playing_sample.state = PlayingState::ATTACK;
-
-                            self.playing_samples.push(playing_sample);
-                        }
-                    }
-                    NoteEvent::NoteOff {
-                        timing: _timing,
-                        voice_id: _voice_id,
-                        channel: _channel,
-                        note: _note,
-                        velocity: _velocity,
-                    } => {
-                        self.playing_samples
-                            .iter_mut()
-                            .for_each(|e| e.state = PlayingState::RELEASE);
-                    }
-                    NoteEvent::MidiSysEx {
-                        timing: _timing,
-                        message,
-                        ..
-                    } => {
-                        if message.is_lyric() {
-                            self.params.lyric_settings.lock().unwrap().lyric_sysex = message;
-                            *self.params.cur_sample.lock().unwrap() = format!(
-                                "{}{}{}.wav",
-                                self.params.singer_dir.lock().unwrap().clone(),
-                                std::path::MAIN_SEPARATOR_STR,
-                                self.lyric
-                            );
-                            nih_log!(
-                                "Received lyric: {}",
-                                self.params
-                                    .lyric_settings
-                                    .lock()
-                                    .unwrap()
-                                    .lyric_sysex
-                                    .get_jpn_utf8()
-                            );
-                        } else {
-                            nih_log!("Received SysEx message: {:?}", message);
-                        }
-                    }
-                    NoteEvent::MidiPitchBend {
-                        timing: _,
-                        channel: _,
-                        value,
-                    } => {
-                        self.pitch_bend = (value - 0.5) * 2.0 * self.params.bend_range.value();
-                        self.midi_frequency = midi_to_hz(self.note as f32 + self.pitch_bend);
-                    }
-                    _ => (),
+            }
+            NoteEvent::NoteOff { note, .. } => {
+                self.note_off(note);
+                self.late_lyric.note_off(note);
+                if record {
+                    self.recording.note_off(beat, note);
+                }
+            }
+            NoteEvent::MidiSysEx {
+                timing: _timing,
+                mut message,
+                ..
+            } => {
+                if message.is_lyric() {
+                    // logged before a late lyric switch moves an NSX-1 message on to its next syllable
+                    nih_log!("Received lyric: {}", message.get_jpn_utf8());
+                    self.params.lyric_settings.lock().unwrap().lyric_sysex = message;
+                    self.switch_late_lyric(time);
+                    *self.params.cur_sample.lock().unwrap() = format!(
+                        "{}{}{}.wav",
+                        self.params.singer_dir.lock().unwrap().clone(),
+                        std::path::MAIN_SEPARATOR_STR,
+                        self.lyric
+                    );
+                } else {
+                    nih_log!("Received SysEx message: {:?}", message);
+                }
+            }
+            NoteEvent::MidiPitchBend {
+                timing: _,
+                channel: _,
+                value,
+            } => {
+                self.pitch_bend = (value - 0.5) * 2.0 * self.params.bend_range.value();
+                if record {
+                    self.recording.pitch_bend(beat, self.pitch_bend * 100.0);
                 }
-                next_event = context.next_event();
             }
+            NoteEvent::MidiCC { cc, value, .. } => {
+                if cc as i32 == self.params.breath_cc.value() {
+                    self.breath_cc_value = value;
+                }
+                if cc == 1 {
+                    self.mod_wheel = value;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Position of an event in beats, on the song's timeline while the transport is playing
+    /// and counted from when the plugin started otherwise
+    fn beat_at(&self, timing: u32) -> f64 {
+        let beats_per_sample = self.tempo / 60.0 / self.sample_rate as f64;
+        match self.song_position {
+            Some(beat) => beat + timing as f64 * beats_per_sample,
+            None => (self.sample_clock + timing as u64) as f64 * beats_per_sample,
         }
     }
 
@@ -719,14 +1524,14 @@ impl Plutau {
 
         let mut oto = Oto::new(oto_path.clone());
         oto.load();
+        oto.script = oto.detect_script();
+        nih_log!("singer aliases are written in {:?}", oto.script);
         *self.params.oto.lock().unwrap() = oto.clone();
 
         oto.contents.iter().for_each(|entry| {
-            let path = path.clone().join(unsafe {
-                std::ffi::OsString::from_encoded_bytes_unchecked(entry.clone().file)
-            });
-            self.load_sample(Path::new(path.as_os_str()).to_path_buf());
+            self.load_sample(entry.file_path(&path));
         });
+        self.find_loops(&oto, &path);
 
         *self.params.singer_dir.lock().unwrap() = path.clone().to_str().unwrap().to_string();
         let singer_name = path
@@ -745,6 +1550,30 @@ impl Plutau {
         );
         *self.params.singer.lock().unwrap() = path.to_str().unwrap().to_string();
     }
+
+    /// Searches for a sustain loop for every oto entry, several entries can share a sample
+    fn find_loops(&mut self, oto: &Oto, singer_dir: &Path) {
+        for entry in oto.contents.iter() {
+            let Some(length) = self
+                .loaded_samples
+                .get(&entry.file_path(singer_dir))
+                .map(|sample| sample.samples[0].len())
+            else {
+                continue;
+            };
+            let (vowel_start, vowel_end) = self.vowel_region(entry, length);
+            let sample_rate = self.sample_rate;
+            if let Some(sample) = self.loaded_samples.get_mut(&entry.file_path(singer_dir)) {
+                let period = sample_rate / sample.frequency;
+                let sustain = find_loop(&sample.samples[0], vowel_start, vowel_end, period);
+                let freeze = find_freeze(&sample.samples[0], sustain, period);
+                sample
+                    .loops
+                    .insert(entry.alias.clone(), SustainLoops { sustain, freeze });
+            }
+        }
+    }
+
     fn remove_singer(&mut self, _path: PathBuf) {
         let keys: Vec<PathBuf> = self.params.sample_list.lock().unwrap().clone();
         for path in keys {
@@ -756,9 +1585,74 @@ impl Plutau {
     }
 
     fn load_lyric(&mut self, path: PathBuf) {
-        if let Ok(contents) = fs::read_to_string(&path) {
-            *self.params.lyrics.lock().unwrap() = contents;
-            self.params.lyric_settings.lock().unwrap().lyric_file = FileLyric::new(path);
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        match extension.as_str() {
+            "mid" | "midi" | "kar" => {
+                let lyric = MidiLyric::new(path);
+                if lyric.timeline.is_empty() {
+                    nih_log!("{} has no lyrics", lyric.path.display());
+                }
+                *self.params.lyrics.lock().unwrap() = lyric.timeline.text();
+                self.params.lyric_settings.lock().unwrap().lyric_midi = lyric;
+            }
+            "ust" => {
+                let lyric = UstLyric::new(path);
+                *self.params.lyrics.lock().unwrap() = lyric.timeline.text();
+                self.params.lyric_settings.lock().unwrap().lyric_ust = lyric;
+            }
+            "ustx" => {
+                let mut lyric = UstxLyric::new(path);
+                lyric.track = self.params.ustx_track.value() as usize - 1;
+                *self.params.lyrics.lock().unwrap() = lyric
+                    .timeline()
+                    .map(|timeline| timeline.text())
+                    .unwrap_or_default();
+                self.params.lyric_settings.lock().unwrap().lyric_ustx = lyric;
+            }
+            _ => {
+                if let Ok(contents) = fs::read_to_string(&path) {
+                    *self.params.lyrics.lock().unwrap() = contents;
+                    self.params.lyric_settings.lock().unwrap().lyric_file = FileLyric::new(path);
+                }
+            }
+        }
+    }
+
+    /// Saves the recorded notes as a .ust or .ustx file, depending on the extension
+    fn export_recording(&mut self, path: PathBuf) {
+        if self.recording.is_empty() {
+            nih_log!("Nothing has been recorded to export");
+            return;
+        }
+        let singer_dir = self.params.singer_dir.lock().unwrap().clone();
+        let singer = Path::new(&singer_dir)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let name = path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let result = match extension.as_str() {
+            "ustx" => self.recording.to_ustx(&name, &singer).write(&path),
+            _ => self.recording.to_ust(&name, &singer_dir).write(&path),
+        };
+        match result {
+            Ok(()) => nih_log!(
+                "Exported {} notes to {}",
+                self.recording.notes.len(),
+                path.display()
+            ),
+            Err(err) => nih_log!("Failed to export {}: {}", path.display(), err),
         }
     }
 }
@@ -1,126 +1,44 @@
 use std::{fs::File, path::PathBuf};
 
+use nih_plug::nih_log;
 use serde::{Deserialize, Serialize};
 
-use crate::{phoneme::Phoneme, sysex::SysExLyric};
+use crate::{
+    expression::NoteExpression,
+    flags::Flags,
+    kana,
+    midi_file::MidiLyric,
+    phoneme::Phoneme,
+    romaji,
+    sysex::SysExLyric,
+    timeline::{LyricTimeline, TimedLyric},
+    ust::UstLyric,
+    ustx::UstxLyric,
+};
 
 pub trait Lyric {
     fn get_jpn_utf8(&mut self) -> String;
     fn get_jpn_jis(&mut self) -> Vec<u8> {
-        let utf8_lut: Vec<&str> = vec![
-            "あ", "い", "う", "え", "お", "か", "き", "く", "け", "こ", "さ", "し", "す", "せ",
-            "そ", "た", "ち", "つ", "て", "と", "な", "に", "ぬ", "ね", "の", "は", "ひ", "ふ",
-            "へ", "ほ", "ま", "み", "む", "め", "も", "や", "", "ゆ", "", "よ", "ら", "り", "る",
-            "れ", "ろ", "わ", "", "", "", "を", "が", "ぎ", "ぐ", "げ", "ご", "ざ", "じ", "ず",
-            "ぜ", "ぞ", "だ", "ぢ", "づ", "で", "ど", "ば", "び", "ぶ", "べ", "ぼ", "ぱ", "ぴ",
-            "ぷ", "ぺ", "ぽ",
-        ];
-        let jis_lut: Vec<&[u8]> = vec![
-            b"\x82\xa0",
-            b"\x82\xa2",
-            b"\x82\xa4",
-            b"\x82\xa6",
-            b"\x82\xa8",
-            // あ, い, う, え, お
-            b"\x82\xa9",
-            b"\x82\xab",
-            b"\x82\xad",
-            b"\x82\xaf",
-            b"\x82\xb1",
-            // か, き, く, け, こ
-            b"\x82\xb3",
-            b"\x82\xb5",
-            b"\x82\xb7",
-            b"\x82\xb9",
-            b"\x82\xbb",
-            // さ, し, す, せ, そ
-            b"\x82\xbd",
-            b"\x82\xbf",
-            b"\x82\xc2",
-            b"\x82\xc4",
-            b"\x82\xc6",
-            // た, ち, つ, て, と
-            b"\x82\xc8",
-            b"\x82\xc9",
-            b"\x82\xca",
-            b"\x82\xcb",
-            b"\x82\xcc",
-            // な, に, ぬ, ね, の
-            b"\x82\xcd",
-            b"\x82\xcf",
-            b"\x82\xd1",
-            b"\x82\xd3",
-            b"\x82\xd5",
-            // は, ひ, ふ, へ, ほ
-            b"\x82\xd7",
-            b"\x82\xd9",
-            b"\x82\xdb",
-            b"\x82\xdd",
-            b"\x82\xdf",
-            // ま, み, む, め, も
-            b"\x82\xe0",
-            b"",
-            b"\x82\xe2",
-            b"",
-            b"\x82\xe4", // や, "", ゆ, "", よ
-            b"\x82\xe6",
-            b"\x82\xe8",
-            b"\x82\xe9",
-            b"\x82\xea",
-            b"\x82\xeb",
-            // ら, り, る, れ, ろ
-            b"\x82\xed",
-            b"",
-            b"",
-            b"",
-            b"\x82\xf0", // わ, "", "", "", を
-            b"\x82\xf2",
-            b"\x82\xf4",
-            b"\x82\xf6",
-            b"\x82\xf8",
-            b"\x82\xfa",
-            // が, ぎ, ぐ, げ, ご
-            b"\x82\xfc",
-            b"\x82\xfd",
-            b"\x82\xfe",
-            b"\x82\xff",
-            b"\x83\x00",
-            // ざ, じ, ず, ぜ, ぞ
-            b"\x83\x01",
-            b"\x83\x02",
-            b"\x83\x03",
-            b"\x83\x04",
-            b"\x83\x05",
-            // だ, ぢ, づ, で, ど
-            b"\x83\x06",
-            b"\x83\x07",
-            b"\x83\x08",
-            b"\x83\x09",
-            b"\x83\x0a",
-            // ば, び, ぶ, べ, ぼ
-            b"\x83\x0b",
-            b"\x83\x0c",
-            b"\x83\x0d",
-            b"\x83\x0e",
-            b"\x83\x0f",
-            // ぱ, ぴ, ぷ, ぺ, ぽ
-        ];
-        let utf8 = self.get_jpn_utf8();
-        //map indexes from utf8_lut to jis_lut
-        let mut jis_vec: Vec<u8> = vec![];
-        for c in utf8.chars() {
-            if let Some(index) = utf8_lut.iter().position(|&x| x.eq(&c.to_string())) {
-                if let Some(jis_bytes) = jis_lut.get(index) {
-                    jis_vec.extend_from_slice(jis_bytes);
-                }
-            }
-        }
-        if jis_vec.is_empty() {
+        let jis = kana::to_shift_jis(&self.get_jpn_utf8());
+        if jis.is_empty() {
             return vec![0x00, 0x00];
         }
-        jis_vec
+        jis
     }
     fn get_latin(&mut self) -> String;
+    /// Per-note flags for the lyric last returned by `get_jpn_utf8`
+    fn get_flags(&mut self) -> Flags {
+        Flags::default()
+    }
+    /// Per-note expression for the lyric last returned by `get_jpn_utf8`
+    fn get_expression(&mut self) -> NoteExpression {
+        NoteExpression::default()
+    }
+    /// Song position in beats of the note about to be started, `None` when the transport isn't playing
+    fn set_position(&mut self, _beat: Option<f64>) {}
+    /// Moves on once a note has sung the lyric returned by `get_jpn_utf8`,
+    /// for sources like NSX-1 SysEx which hold several lyrics but don't know the song position
+    fn advance(&mut self) {}
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -128,6 +46,9 @@ pub enum LyricSource {
     Param,
     File,
     SysEx,
+    Midi,
+    Ust,
+    Ustx,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -136,6 +57,12 @@ pub struct LyricSettings {
     pub lyric_file: FileLyric,
     pub lyric_sysex: SysExLyric,
     pub lyric_param: ParamLyric,
+    #[serde(default)]
+    pub lyric_midi: MidiLyric,
+    #[serde(default)]
+    pub lyric_ust: UstLyric,
+    #[serde(default)]
+    pub lyric_ustx: UstxLyric,
 }
 
 impl Lyric for LyricSettings {
@@ -145,6 +72,9 @@ impl Lyric for LyricSettings {
             LyricSource::Param => self.lyric_param.get_jpn_utf8(),
             LyricSource::File => self.lyric_file.get_jpn_utf8(),
             LyricSource::SysEx => self.lyric_sysex.get_jpn_utf8(),
+            LyricSource::Midi => self.lyric_midi.get_jpn_utf8(),
+            LyricSource::Ust => self.lyric_ust.get_jpn_utf8(),
+            LyricSource::Ustx => self.lyric_ustx.get_jpn_utf8(),
         }
     }
 
@@ -153,6 +83,53 @@ impl Lyric for LyricSettings {
             LyricSource::Param => self.lyric_param.get_latin(),
             LyricSource::File => self.lyric_file.get_latin(),
             LyricSource::SysEx => self.lyric_sysex.get_latin(),
+            LyricSource::Midi => self.lyric_midi.get_latin(),
+            LyricSource::Ust => self.lyric_ust.get_latin(),
+            LyricSource::Ustx => self.lyric_ustx.get_latin(),
+        }
+    }
+
+    fn get_flags(&mut self) -> Flags {
+        match self.lyric_source {
+            LyricSource::Param => self.lyric_param.get_flags(),
+            LyricSource::File => self.lyric_file.get_flags(),
+            LyricSource::SysEx => self.lyric_sysex.get_flags(),
+            LyricSource::Midi => self.lyric_midi.get_flags(),
+            LyricSource::Ust => self.lyric_ust.get_flags(),
+            LyricSource::Ustx => self.lyric_ustx.get_flags(),
+        }
+    }
+
+    fn get_expression(&mut self) -> NoteExpression {
+        match self.lyric_source {
+            LyricSource::Param => self.lyric_param.get_expression(),
+            LyricSource::File => self.lyric_file.get_expression(),
+            LyricSource::SysEx => self.lyric_sysex.get_expression(),
+            LyricSource::Midi => self.lyric_midi.get_expression(),
+            LyricSource::Ust => self.lyric_ust.get_expression(),
+            LyricSource::Ustx => self.lyric_ustx.get_expression(),
+        }
+    }
+
+    fn set_position(&mut self, beat: Option<f64>) {
+        match self.lyric_source {
+            LyricSource::Param => self.lyric_param.set_position(beat),
+            LyricSource::File => self.lyric_file.set_position(beat),
+            LyricSource::SysEx => self.lyric_sysex.set_position(beat),
+            LyricSource::Midi => self.lyric_midi.set_position(beat),
+            LyricSource::Ust => self.lyric_ust.set_position(beat),
+            LyricSource::Ustx => self.lyric_ustx.set_position(beat),
+        }
+    }
+
+    fn advance(&mut self) {
+        match self.lyric_source {
+            LyricSource::Param => self.lyric_param.advance(),
+            LyricSource::File => self.lyric_file.advance(),
+            LyricSource::SysEx => self.lyric_sysex.advance(),
+            LyricSource::Midi => self.lyric_midi.advance(),
+            LyricSource::Ust => self.lyric_ust.advance(),
+            LyricSource::Ustx => self.lyric_ustx.advance(),
         }
     }
 }
@@ -166,6 +143,9 @@ impl LyricSettings {
             lyric_param: ParamLyric {
                 current: Phoneme::default(),
             },
+            lyric_midi: MidiLyric::default(),
+            lyric_ust: UstLyric::default(),
+            lyric_ustx: UstxLyric::default(),
         }
     }
 
@@ -179,15 +159,43 @@ pub struct FileLyric {
     pub path: PathBuf,
     pub lyric_vec: Vec<String>,
     pub index: usize,
+    /// Per-note flags, written after the lyric as `か:g-5B60`
+    #[serde(default)]
+    pub flag_vec: Vec<Flags>,
+    #[serde(default)]
+    pub flags: Flags,
+    /// Song positions of the lyrics, learned while playing the song through once
+    #[serde(default)]
+    pub timeline: LyricTimeline,
+    /// Whether every lyric has been given a position, partly learned positions are dropped on stop
+    #[serde(default)]
+    pub learned: bool,
+    #[serde(skip)]
+    position: Option<f64>,
 }
 
 impl Lyric for FileLyric {
     fn get_jpn_utf8(&mut self) -> String {
+        // notes which have been played before keep their lyric, wherever playback starts
+        if let Some(index) = self.position.and_then(|beat| self.timeline.find(beat)) {
+            let entry = &self.timeline.entries()[index];
+            self.flags = entry.flags;
+            return entry.lyric.clone();
+        }
         if self.index < self.lyric_vec.len() {
             let lyric = self.lyric_vec[self.index].clone();
+            self.flags = self.flag_vec.get(self.index).copied().unwrap_or_default();
+            if let Some(beat) = self.position {
+                self.timeline
+                    .insert(TimedLyric::new(beat, lyric.clone(), self.flags));
+            }
             self.index += 1; // Increment index for next call
             if self.index >= self.lyric_vec.len() {
                 self.index = 0; // Reset index if it exceeds the length
+                if self.position.is_some() && !self.learned {
+                    self.learned = true;
+                    nih_log!("Learned the positions of {} lyrics", self.lyric_vec.len());
+                }
             }
             lyric
         } else {
@@ -196,15 +204,21 @@ impl Lyric for FileLyric {
     }
 
     fn get_latin(&mut self) -> String {
-        //TODO: translation from jpn to romaji
-        // For now, just return the same as jpn_utf8
-        self.get_jpn_utf8()
+        romaji::to_romaji(&self.get_jpn_utf8())
+    }
+
+    fn get_flags(&mut self) -> Flags {
+        self.flags
+    }
+
+    fn set_position(&mut self, beat: Option<f64>) {
+        self.position = beat;
     }
 }
 
 impl FileLyric {
     pub fn new(path: PathBuf) -> Self {
-        let lyric_vec = match std::fs::read_to_string(&path) {
+        let chunks: Vec<String> = match std::fs::read_to_string(&path) {
             Ok(content) => content
                 .split_whitespace()
                 .map(|chunk| chunk.to_string())
@@ -214,13 +228,33 @@ impl FileLyric {
                 Vec::new()
             }
         };
+        let (lyric_vec, flag_vec) = chunks
+            .iter()
+            .map(|chunk| match chunk.split_once(':') {
+                Some((lyric, flags)) => (lyric.to_string(), Flags::parse(flags)),
+                None => (chunk.clone(), Flags::default()),
+            })
+            .unzip();
         Self {
             path,
             lyric_vec,
             index: 0,
+            flag_vec,
+            flags: Flags::default(),
+            timeline: LyricTimeline::new(),
+            learned: false,
+            position: None,
         }
     }
 
+    /// Goes back to the first lyric when the transport stops.
+    /// Positions learned so far are dropped unless the whole song was played through.
+    pub fn stop(&mut self) {
+        self.index = 0;
+        if !self.learned {
+            self.timeline.clear();
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -238,3 +272,65 @@ impl Lyric for ParamLyric {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_lyric(name: &str, contents: &str) -> FileLyric {
+        let path = std::env::temp_dir().join(format!("plutau-{}-{}.txt", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let lyric = FileLyric::new(path.clone());
+        std::fs::remove_file(path).unwrap();
+        lyric
+    }
+
+    fn sing(lyric: &mut FileLyric, beat: Option<f64>) -> String {
+        lyric.set_position(beat);
+        lyric.get_jpn_utf8()
+    }
+
+    #[test]
+    fn reads_lyrics_and_flags() {
+        let mut lyric = file_lyric("flags", "あ か:g-5B60\nさ");
+        assert_eq!(lyric.lyric_vec, ["あ", "か", "さ"]);
+        assert_eq!(sing(&mut lyric, None), "あ");
+        assert_eq!(lyric.get_flags(), Flags::default());
+        assert_eq!(sing(&mut lyric, None), "か");
+        assert_eq!(lyric.get_flags(), Flags::parse("g-5B60"));
+        assert_eq!(sing(&mut lyric, None), "さ");
+        // and round again
+        assert_eq!(sing(&mut lyric, None), "あ");
+    }
+
+    #[test]
+    fn missing_file_has_no_lyrics() {
+        let mut lyric = FileLyric::new(PathBuf::from("/nonexistent/lyrics.txt"));
+        assert_eq!(lyric.get_jpn_utf8(), "");
+    }
+
+    #[test]
+    fn learns_positions_while_playing_through() {
+        let mut lyric = file_lyric("learn", "あ か:g-5 さ");
+        for (beat, expected) in [(0.0, "あ"), (1.0, "か"), (2.0, "さ")] {
+            assert_eq!(sing(&mut lyric, Some(beat)), expected);
+        }
+        assert!(lyric.learned);
+
+        // starting from the middle of the song still finds the right lyric
+        lyric.stop();
+        assert_eq!(sing(&mut lyric, Some(1.0)), "か");
+        assert_eq!(lyric.get_flags(), Flags::parse("g-5"));
+        assert_eq!(sing(&mut lyric, Some(2.0)), "さ");
+    }
+
+    #[test]
+    fn stopping_early_forgets_positions() {
+        let mut lyric = file_lyric("stop", "あ か さ");
+        assert_eq!(sing(&mut lyric, Some(0.0)), "あ");
+        assert_eq!(sing(&mut lyric, Some(1.0)), "か");
+        lyric.stop();
+        assert!(!lyric.learned);
+        assert!(lyric.timeline.is_empty());
+        assert_eq!(sing(&mut lyric, Some(1.0)), "あ");
+    }
+}
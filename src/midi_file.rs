@@ -0,0 +1,233 @@
+use std::path::PathBuf;
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use nih_plug::nih_log;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    flags::Flags,
+    lyrics::Lyric,
+    romaji,
+    timeline::{LyricTimeline, TimedLyric},
+};
+
+/// How far before its note a lyric event can be, in beats
+const NOTE_SEARCH: f64 = 0.5;
+
+/// Lyrics imported from the lyric or text meta events of a standard MIDI file (`.mid`, `.kar`)
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MidiLyric {
+    pub path: PathBuf,
+    pub timeline: LyricTimeline,
+    /// Song position of the note being started, `None` when the transport isn't playing
+    #[serde(skip)]
+    position: Option<f64>,
+    /// Next lyric when there is no song position to look it up by
+    #[serde(skip)]
+    pub index: usize,
+    #[serde(skip)]
+    flags: Flags,
+}
+
+impl MidiLyric {
+    pub fn new(path: PathBuf) -> Self {
+        let timeline = match std::fs::read(&path) {
+            Ok(bytes) => read_timeline(&bytes).unwrap_or_else(|err| {
+                nih_log!("Failed to read MIDI file {}: {}", path.display(), err);
+                LyricTimeline::new()
+            }),
+            Err(err) => {
+                nih_log!("Failed to read file {}: {}", path.display(), err);
+                LyricTimeline::new()
+            }
+        };
+        Self {
+            path,
+            timeline,
+            ..Default::default()
+        }
+    }
+}
+
+impl Lyric for MidiLyric {
+    fn get_jpn_utf8(&mut self) -> String {
+        let entry = self.timeline.next(self.position, &mut self.index);
+        self.flags = entry.map(|entry| entry.flags).unwrap_or_default();
+        entry.map(|entry| entry.lyric.clone()).unwrap_or_default()
+    }
+
+    fn get_latin(&mut self) -> String {
+        romaji::to_romaji(&self.get_jpn_utf8())
+    }
+
+    fn get_flags(&mut self) -> Flags {
+        self.flags
+    }
+
+    fn set_position(&mut self, beat: Option<f64>) {
+        self.position = beat;
+    }
+}
+
+/// Reads the lyric events of a MIDI file into a timeline, moving each lyric to the note it belongs to
+fn read_timeline(bytes: &[u8]) -> Result<LyricTimeline, String> {
+    let smf = Smf::parse(bytes).map_err(|err| err.to_string())?;
+    let ticks_per_beat = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int() as f64,
+        Timing::Timecode(..) => return Err(String::from("timecode based files aren't supported")),
+    };
+
+    let mut lyrics = vec![];
+    let mut texts = vec![];
+    let mut notes = vec![];
+    for track in smf.tracks.iter() {
+        let mut tick = 0u64;
+        for event in track.iter() {
+            tick += event.delta.as_int() as u64;
+            let beat = tick as f64 / ticks_per_beat;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Lyric(text)) => lyrics.push((beat, text)),
+                TrackEventKind::Meta(MetaMessage::Text(text)) => texts.push((beat, text)),
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { vel, .. },
+                    ..
+                } if vel.as_int() > 0 => notes.push(beat),
+                _ => {}
+            }
+        }
+    }
+    notes.sort_by(f64::total_cmp);
+
+    // text events are only used when there are no lyric events, as in most .kar files
+    let events = if lyrics.is_empty() { texts } else { lyrics };
+    let mut timeline = LyricTimeline::new();
+    for (beat, text) in events {
+        let Some(lyric) = clean_lyric(&decode(text)) else {
+            continue;
+        };
+        // lyric events often come just before their note rather than exactly on it
+        let beat = notes
+            .iter()
+            .find(|note| **note >= beat && **note - beat <= NOTE_SEARCH)
+            .copied()
+            .unwrap_or(beat);
+        timeline.insert(TimedLyric::new(beat, lyric, Flags::default()));
+    }
+    Ok(timeline)
+}
+
+/// Decodes text which is UTF-8 in newer files and Shift-JIS in most Japanese ones
+pub fn decode(text: &[u8]) -> String {
+    match std::str::from_utf8(text) {
+        Ok(text) => text.to_string(),
+        Err(_) => encoding_rs::SHIFT_JIS.decode(text).0.into_owned(),
+    }
+}
+
+/// Strips karaoke markup from a lyric, `None` for headers and empty lyrics
+fn clean_lyric(text: &str) -> Option<String> {
+    // `@` starts .kar header fields, `/` and `\` mark new lines and paragraphs
+    if text.starts_with('@') {
+        return None;
+    }
+    let lyric = text
+        .trim_start_matches(['/', '\\'])
+        .trim()
+        .trim_end_matches('-')
+        .trim();
+    if lyric.is_empty() {
+        None
+    } else {
+        Some(lyric.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A format 0 file with 480 ticks per beat, from `(delta, event)` pairs
+    fn smf(events: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut track = vec![];
+        for (delta, event) in events.iter().chain([&(0, vec![0xff, 0x2f, 0x00])]) {
+            let mut bytes = vec![(delta & 0x7f) as u8];
+            let mut rest = delta >> 7;
+            while rest > 0 {
+                bytes.insert(0, (rest & 0x7f) as u8 | 0x80);
+                rest >>= 7;
+            }
+            track.extend(bytes);
+            track.extend(event);
+        }
+        let mut file = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x01\xe0MTrk".to_vec();
+        file.extend((track.len() as u32).to_be_bytes());
+        file.extend(track);
+        file
+    }
+
+    fn meta(kind: u8, text: &[u8]) -> Vec<u8> {
+        let mut event = vec![0xff, kind, text.len() as u8];
+        event.extend(text);
+        event
+    }
+
+    fn note_on(note: u8) -> Vec<u8> {
+        vec![0x90, note, 0x64]
+    }
+
+    fn lyrics(timeline: &LyricTimeline) -> Vec<(f64, &str)> {
+        timeline
+            .entries()
+            .iter()
+            .map(|entry| (entry.beat, entry.lyric.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn lyrics_move_to_their_notes() {
+        let bytes = smf(&[
+            (0, meta(0x05, "か".as_bytes())),
+            (120, note_on(60)),
+            (360, note_on(62)),
+            (0, meta(0x05, "ら".as_bytes())),
+            // too far before any note to belong to one
+            (480, meta(0x05, "ん".as_bytes())),
+        ]);
+        let timeline = read_timeline(&bytes).unwrap();
+        assert_eq!(lyrics(&timeline), [(0.25, "か"), (1.0, "ら"), (2.0, "ん")]);
+    }
+
+    #[test]
+    fn text_events_are_used_without_lyric_events() {
+        let karaoke = smf(&[
+            (0, meta(0x01, b"@TTitle")),
+            (0, meta(0x01, b"/sa")),
+            (0, note_on(60)),
+            (480, meta(0x01, b"\\ku-")),
+            (0, note_on(62)),
+            (480, meta(0x01, b" ")),
+        ]);
+        assert_eq!(
+            lyrics(&read_timeline(&karaoke).unwrap()),
+            [(0.0, "sa"), (1.0, "ku")]
+        );
+
+        let both = smf(&[(0, meta(0x01, b"text")), (0, meta(0x05, b"lyric"))]);
+        assert_eq!(lyrics(&read_timeline(&both).unwrap()), [(0.0, "lyric")]);
+    }
+
+    #[test]
+    fn decodes_shift_jis_and_utf8() {
+        assert_eq!(decode(&[0x82, 0xa0, 0x83, 0x4a]), "あカ");
+        assert_eq!(decode("あカ".as_bytes()), "あカ");
+    }
+
+    #[test]
+    fn rejects_timecode_files() {
+        let mut bytes = smf(&[(0, meta(0x05, b"a"))]);
+        bytes[12] = 0xe7;
+        bytes[13] = 0x28;
+        assert!(read_timeline(&bytes).is_err());
+        assert!(read_timeline(b"not a midi file").is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use nih_plug::prelude::Enum;
+
+/// Which held note sounds when several are held at once
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotePriority {
+    Last,
+    Lowest,
+    Highest,
+}
+
+/// Notes which are currently held down, in the order they were pressed
+#[derive(Debug, Clone, Default)]
+pub struct NoteStack {
+    notes: Vec<u8>,
+}
+
+impl NoteStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, note: u8) {
+        self.remove(note);
+        self.notes.push(note);
+    }
+
+    pub fn remove(&mut self, note: u8) {
+        self.notes.retain(|held| *held != note);
+    }
+
+    /// The note that should be sounding, `None` if no notes are held
+    pub fn top(&self, priority: NotePriority) -> Option<u8> {
+        match priority {
+            NotePriority::Last => self.notes.last().copied(),
+            NotePriority::Lowest => self.notes.iter().min().copied(),
+            NotePriority::Highest => self.notes.iter().max().copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(notes: &[u8]) -> NoteStack {
+        let mut stack = NoteStack::new();
+        notes.iter().for_each(|note| stack.push(*note));
+        stack
+    }
+
+    #[test]
+    fn priorities_pick_the_sounding_note() {
+        let stack = stack(&[60, 67, 55, 64]);
+        assert_eq!(stack.top(NotePriority::Last), Some(64));
+        assert_eq!(stack.top(NotePriority::Highest), Some(67));
+        assert_eq!(stack.top(NotePriority::Lowest), Some(55));
+        assert_eq!(NoteStack::new().top(NotePriority::Last), None);
+    }
+
+    #[test]
+    fn releasing_falls_back_to_a_held_note() {
+        let mut stack = stack(&[60, 64, 62]);
+        stack.remove(62);
+        assert_eq!(stack.top(NotePriority::Last), Some(64));
+        stack.remove(64);
+        assert_eq!(stack.top(NotePriority::Last), Some(60));
+        stack.remove(60);
+        assert_eq!(stack.top(NotePriority::Last), None);
+    }
+
+    #[test]
+    fn releasing_other_notes_keeps_the_sounding_note() {
+        let mut stack = stack(&[60, 64, 62]);
+        stack.remove(60);
+        stack.remove(70);
+        assert_eq!(stack.top(NotePriority::Last), Some(62));
+        assert_eq!(stack.top(NotePriority::Highest), Some(64));
+        // pressing a held note again makes it the last one
+        stack.push(64);
+        assert_eq!(stack.top(NotePriority::Last), Some(64));
+        stack.remove(64);
+        assert_eq!(stack.top(NotePriority::Last), Some(62));
+    }
+}
@@ -0,0 +1,142 @@
+/// Start of every Yamaha NSX-1 SysEx message, Yamaha's ID followed by the NSX-1's
+pub const HEADER: [u8; 4] = [0xf0, 0x43, 0x79, 0x09];
+/// Header of a Yamaha NSX-1 lyric message, followed by phonetic symbols, `00` and `F7`
+pub const LYRIC_HEADER: [u8; 7] = [0xf0, 0x43, 0x79, 0x09, 0x00, 0x50, 0x10];
+
+/// VOCALOID Japanese phonetic symbols used by the NSX-1, with the kana they are sung as
+const SYMBOLS: [(&str, &str); 110] = [
+    ("a", "あ"),
+    ("i", "い"),
+    ("M", "う"),
+    ("e", "え"),
+    ("o", "お"),
+    ("k a", "か"),
+    ("k' i", "き"),
+    ("k M", "く"),
+    ("k e", "け"),
+    ("k o", "こ"),
+    ("g a", "が"),
+    ("g' i", "ぎ"),
+    ("g M", "ぐ"),
+    ("g e", "げ"),
+    ("g o", "ご"),
+    ("s a", "さ"),
+    ("S i", "し"),
+    ("s M", "す"),
+    ("s e", "せ"),
+    ("s o", "そ"),
+    ("dz a", "ざ"),
+    ("dZ i", "じ"),
+    ("dz M", "ず"),
+    ("dz e", "ぜ"),
+    ("dz o", "ぞ"),
+    ("t a", "た"),
+    ("tS i", "ち"),
+    ("ts M", "つ"),
+    ("t e", "て"),
+    ("t o", "と"),
+    ("d a", "だ"),
+    ("d e", "で"),
+    ("d o", "ど"),
+    ("n a", "な"),
+    ("J i", "に"),
+    ("n M", "ぬ"),
+    ("n e", "ね"),
+    ("n o", "の"),
+    ("h a", "は"),
+    ("C i", "ひ"),
+    ("p\\ M", "ふ"),
+    ("h e", "へ"),
+    ("h o", "ほ"),
+    ("b a", "ば"),
+    ("b' i", "び"),
+    ("b M", "ぶ"),
+    ("b e", "べ"),
+    ("b o", "ぼ"),
+    ("p a", "ぱ"),
+    ("p' i", "ぴ"),
+    ("p M", "ぷ"),
+    ("p e", "ぺ"),
+    ("p o", "ぽ"),
+    ("m a", "ま"),
+    ("m' i", "み"),
+    ("m M", "む"),
+    ("m e", "め"),
+    ("m o", "も"),
+    ("j a", "や"),
+    ("j M", "ゆ"),
+    ("j o", "よ"),
+    ("4 a", "ら"),
+    ("4' i", "り"),
+    ("4 M", "る"),
+    ("4 e", "れ"),
+    ("4 o", "ろ"),
+    ("w a", "わ"),
+    ("w o", "を"),
+    ("N\\", "ん"),
+    ("k' a", "きゃ"),
+    ("k' M", "きゅ"),
+    ("k' o", "きょ"),
+    ("g' a", "ぎゃ"),
+    ("g' M", "ぎゅ"),
+    ("g' o", "ぎょ"),
+    ("S a", "しゃ"),
+    ("S M", "しゅ"),
+    ("S o", "しょ"),
+    ("dZ a", "じゃ"),
+    ("dZ M", "じゅ"),
+    ("dZ o", "じょ"),
+    ("tS a", "ちゃ"),
+    ("tS M", "ちゅ"),
+    ("tS o", "ちょ"),
+    ("J a", "にゃ"),
+    ("J M", "にゅ"),
+    ("J o", "にょ"),
+    ("C a", "ひゃ"),
+    ("C M", "ひゅ"),
+    ("C o", "ひょ"),
+    ("b' a", "びゃ"),
+    ("b' M", "びゅ"),
+    ("b' o", "びょ"),
+    ("p' a", "ぴゃ"),
+    ("p' M", "ぴゅ"),
+    ("p' o", "ぴょ"),
+    ("m' a", "みゃ"),
+    ("m' M", "みゅ"),
+    ("m' o", "みょ"),
+    ("4' a", "りゃ"),
+    ("4' M", "りゅ"),
+    ("4' o", "りょ"),
+    ("t' i", "てぃ"),
+    ("d' i", "でぃ"),
+    ("p\\ a", "ふぁ"),
+    ("p\\' i", "ふぃ"),
+    ("p\\ e", "ふぇ"),
+    ("p\\ o", "ふぉ"),
+    ("w i", "うぃ"),
+    ("w e", "うぇ"),
+];
+
+/// Other symbols the NSX-1 accepts for the same sound
+const ALIASES: [(&str, &str); 6] = [
+    ("N", "N\\"),
+    ("N'", "N\\"),
+    ("m", "N\\"),
+    ("m'", "N\\"),
+    ("n", "N\\"),
+    ("J", "N\\"),
+];
+
+/// Kana for one syllable of phonetic symbols like `k' a`, `None` if it isn't in the table
+pub fn symbols_to_kana(symbols: &str) -> Option<&'static str> {
+    // the symbols are separated by single spaces in the table
+    let symbols = symbols.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let symbols = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == symbols)
+        .map_or(symbols.as_str(), |(_, symbols)| symbols);
+    SYMBOLS
+        .iter()
+        .find(|(known, _)| *known == symbols)
+        .map(|(_, kana)| *kana)
+}
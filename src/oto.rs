@@ -1,11 +1,19 @@
-use std::io::BufRead;
+use std::{
+    io::BufRead,
+    path::{Path, PathBuf},
+};
 
 use serde::{Deserialize, Serialize};
 
+use crate::kana::Script;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Oto {
     pub path: String,
     pub contents: Vec<OtoEntry>,
+    /// Script the aliases are written in, detected when the singer is loaded
+    #[serde(default)]
+    pub script: Script,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,11 +28,21 @@ pub struct OtoEntry {
 
 }
 
+impl OtoEntry {
+    /// Path of the entry's wav file inside the singer's directory
+    pub fn file_path(&self, singer_dir: &Path) -> PathBuf {
+        // file names are stored as raw bytes so banks which aren't UTF-8 still load
+        singer_dir
+            .join(unsafe { std::ffi::OsString::from_encoded_bytes_unchecked(self.file.clone()) })
+    }
+}
+
 impl Oto {
     pub fn new(path: String) -> Self {
         Self {
             path,
             contents: Vec::new(),
+            script: Script::default(),
         }
     }
 
@@ -37,7 +55,15 @@ impl Oto {
 
             let file: Vec<u8> = line.split("=").next().unwrap().as_bytes().to_vec();
 
-            let alias: Vec<u8> = split.next().unwrap().as_bytes().to_vec();
+            // the first field is `file.wav=alias`
+            let alias: Vec<u8> = split
+                .next()
+                .unwrap()
+                .split_once("=")
+                .map(|(_, alias)| alias)
+                .unwrap_or_default()
+                .as_bytes()
+                .to_vec();
             let offset = split.next().unwrap().parse().unwrap();
             let consonant = split.next().unwrap().parse().unwrap();
             let cutoff = split.next().unwrap().parse().unwrap();
@@ -56,8 +82,88 @@ impl Oto {
         }
     }
 
+    /// Finds the script most aliases are written in. Only the last part of an alias counts,
+    /// so the `a` of a VCV alias like `a か` doesn't make it look romaji.
+    /// Entries without an alias are named by their file.
+    pub fn detect_script(&self) -> Script {
+        let mut counts = [0; 3];
+        for entry in self.contents.iter() {
+            let name = match entry.alias.is_empty() {
+                true => {
+                    let file = String::from_utf8_lossy(&entry.file).to_string();
+                    let file = file.rsplit(['/', '\\']).next().unwrap_or_default();
+                    file.rsplit_once('.')
+                        .map_or(file, |(stem, _)| stem)
+                        .to_string()
+                }
+                false => String::from_utf8_lossy(&entry.alias).to_string(),
+            };
+            if let Some(script) = name.split_whitespace().last().and_then(Script::of) {
+                counts[script as usize] += 1;
+            }
+        }
+        // ties go to hiragana, the most common
+        [Script::Romaji, Script::Katakana, Script::Hiragana]
+            .into_iter()
+            .max_by_key(|script| counts[*script as usize])
+            .unwrap_or_default()
+    }
+
+    pub fn get_alias(&self, alias: &str) -> Option<&OtoEntry> {
+        self.contents
+            .iter()
+            .find(|entry| entry.alias == alias.as_bytes())
+    }
+
     pub fn get_entry(&self, file: String) -> Option<&OtoEntry> {
         nih_plug::nih_log!("{}", file);
         self.contents.iter().find(|entry| String::from_utf8(entry.file.clone()).unwrap_or_default() == file)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oto(entries: &[(&str, &str)]) -> Oto {
+        let mut oto = Oto::new(String::new());
+        for (file, alias) in entries {
+            oto.contents.push(OtoEntry {
+                file: file.as_bytes().to_vec(),
+                alias: alias.as_bytes().to_vec(),
+                offset: 0,
+                consonant: 0,
+                cutoff: 0,
+                preutterance: 0,
+                overlap: 0,
+            });
+        }
+        oto
+    }
+
+    #[test]
+    fn detects_the_script_of_the_aliases() {
+        let vcv = oto(&[
+            ("_かさ.wav", "- か"),
+            ("_かさ.wav", "a さ"),
+            ("_ka.wav", "a ka"),
+        ]);
+        assert_eq!(vcv.detect_script(), Script::Hiragana);
+        let katakana = oto(&[("カ.wav", "カ"), ("サ.wav", "サ")]);
+        assert_eq!(katakana.detect_script(), Script::Katakana);
+    }
+
+    #[test]
+    fn falls_back_to_the_file_name() {
+        let romaji = oto(&[("A3/ka.wav", ""), ("A3\\sa.wav", ""), ("A3/あ.wav", "")]);
+        assert_eq!(romaji.detect_script(), Script::Romaji);
+    }
+
+    #[test]
+    fn ties_and_unknown_scripts_go_to_hiragana() {
+        assert_eq!(oto(&[]).detect_script(), Script::Hiragana);
+        assert_eq!(oto(&[("息.wav", "息")]).detect_script(), Script::Hiragana);
+        let tie = oto(&[("ka.wav", "ka"), ("カ.wav", "カ"), ("か.wav", "か")]);
+        assert_eq!(tie.detect_script(), Script::Hiragana);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::breath::Noise;
+
+/// Settings shared by every voice's pitch curve, read from the params once per block
+#[derive(Debug, Clone, Copy)]
+pub struct PitchCurveSettings {
+    /// How far past the target pitch a transition goes, in cents
+    pub overshoot: f32,
+    /// How long the overshoot takes to settle, in seconds
+    pub overshoot_time: f32,
+    /// How far below the pitch a note starts, in cents
+    pub preparation: f32,
+    /// How long the start of a note takes to rise to pitch, in seconds
+    pub preparation_time: f32,
+    /// Depth of the slow random drift, in cents
+    pub drift: f32,
+    /// How often the drift picks a new direction, in Hz
+    pub drift_rate: f32,
+}
+
+/// Per-voice automatic pitch curve, layered on top of the note pitch like OpenUtau's default curves
+#[derive(Debug, Clone, Copy)]
+pub struct PitchCurve {
+    /// Seconds since the note started
+    age: f32,
+    /// Seconds until the current overshoot starts, it waits for the glide to arrive
+    overshoot_delay: f32,
+    /// Seconds since the current overshoot started
+    overshoot_age: f32,
+    /// 1.0 when the last transition went up, -1.0 when it went down, 0.0 for no transition
+    direction: f32,
+    drift_from: f32,
+    drift_to: f32,
+    drift_phase: f32,
+    noise: Noise,
+}
+
+impl PitchCurve {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            age: 0.0,
+            overshoot_delay: 0.0,
+            overshoot_age: 0.0,
+            direction: 0.0,
+            drift_from: 0.0,
+            drift_to: 0.0,
+            drift_phase: 0.0,
+            noise: Noise::new(seed),
+        }
+    }
+
+    /// Starts an overshoot for a legato transition once the glide of `glide_time` seconds arrives
+    pub fn transition(&mut self, direction: f32, glide_time: f32) {
+        self.direction = direction.signum();
+        self.overshoot_delay = glide_time;
+        self.overshoot_age = 0.0;
+    }
+
+    /// Current pitch offset in cents
+    pub fn cents(&self, settings: &PitchCurveSettings) -> f32 {
+        let mut cents = 0.0;
+
+        // rise into the first note from below
+        if self.age < settings.preparation_time {
+            let remaining = 1.0 - self.age / settings.preparation_time;
+            cents -= settings.preparation * remaining * remaining;
+        }
+
+        // go past the new note and settle back onto it
+        if self.overshoot_delay <= 0.0 && self.overshoot_age < settings.overshoot_time {
+            let progress = self.overshoot_age / settings.overshoot_time;
+            cents += self.direction
+                * settings.overshoot
+                * (std::f32::consts::PI * progress).sin()
+                * (1.0 - progress);
+        }
+
+        // cosine interpolation between random drift targets
+        let smooth = 0.5 - 0.5 * (std::f32::consts::PI * self.drift_phase).cos();
+        cents += (self.drift_from + (self.drift_to - self.drift_from) * smooth) * settings.drift;
+
+        cents
+    }
+
+    pub fn advance(&mut self, seconds: f32, settings: &PitchCurveSettings) {
+        self.age += seconds;
+
+        if self.overshoot_delay > 0.0 {
+            self.overshoot_delay -= seconds;
+        } else {
+            self.overshoot_age += seconds;
+        }
+
+        self.drift_phase += seconds * settings.drift_rate;
+        while self.drift_phase >= 1.0 {
+            self.drift_phase -= 1.0;
+            self.drift_from = self.drift_to;
+            self.drift_to = self.noise.sample();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: PitchCurveSettings = PitchCurveSettings {
+        overshoot: 50.0,
+        overshoot_time: 0.2,
+        preparation: 100.0,
+        preparation_time: 0.1,
+        drift: 0.0,
+        drift_rate: 5.0,
+    };
+
+    #[test]
+    fn preparation_dips_only_at_the_start() {
+        let mut curve = PitchCurve::new(1);
+        assert_eq!(curve.cents(&SETTINGS), -100.0);
+        curve.advance(0.05, &SETTINGS);
+        assert!((curve.cents(&SETTINGS) + 25.0).abs() < 1e-3);
+        let mut previous = curve.cents(&SETTINGS);
+        for step in 0..100 {
+            curve.advance(0.001, &SETTINGS);
+            let cents = curve.cents(&SETTINGS);
+            assert!(cents >= previous, "{step}");
+            previous = cents;
+        }
+        curve.advance(0.01, &SETTINGS);
+        assert_eq!(curve.cents(&SETTINGS), 0.0);
+        curve.advance(1.0, &SETTINGS);
+        assert_eq!(curve.cents(&SETTINGS), 0.0);
+    }
+
+    #[test]
+    fn overshoot_waits_for_the_glide_and_settles() {
+        let settings = PitchCurveSettings {
+            preparation: 0.0,
+            ..SETTINGS
+        };
+        let mut curve = PitchCurve::new(1);
+        curve.transition(1.0, 0.05);
+        curve.advance(0.04, &settings);
+        assert_eq!(curve.cents(&settings), 0.0);
+        curve.advance(0.02, &settings);
+        curve.advance(0.01, &settings);
+        assert!(curve.cents(&settings) > 0.0);
+        curve.advance(0.04, &settings);
+        assert!(curve.cents(&settings) > 10.0);
+        curve.advance(0.2, &settings);
+        assert_eq!(curve.cents(&settings), 0.0);
+
+        // going down overshoots below the note
+        curve.transition(-1.0, 0.0);
+        curve.advance(0.05, &settings);
+        assert!(curve.cents(&settings) < -10.0);
+    }
+}
@@ -1,13 +1,58 @@
 use std::path::PathBuf;
 
+use crate::{
+    envelope::Adsr,
+    expression::NoteExpression,
+    flags::Flags,
+    pitch_curve::PitchCurve,
+    portamento::Glide,
+    sustain::{LoopPoints, StretchRegion, SustainMode, TimeStretch},
+    synth::Rendered,
+    vibrato::Vibrato,
+};
+
 pub struct PlayingSample {
     pub handle: PathBuf,
     pub position: isize,
+    /// Fraction of a sample past `position`, as playback runs faster or slower to follow the pitch
+    pub phase: f32,
     pub gain: f32,
     pub state: PlayingState,
     pub vowel_start: u32,
     pub vowel_end: u32,
-    pub ignore_fade: bool
+    pub sustain_loop: LoopPoints,
+    pub sustain_mode: SustainMode,
+    /// Set when the consonant plays at a different speed, positions are then in the stretched timing
+    pub stretch: Option<StretchRegion>,
+    /// Repeats periods of the vowel while the note is held in the time-stretch sustain mode
+    pub time_stretch: Option<TimeStretch>,
+    /// Periods of the frozen frame played since a freeze reached it, from 1.0 once it has faded in.
+    /// Playback stays on the frame from then on.
+    pub frozen: Option<f32>,
+    /// Playing backwards through a ping-pong loop
+    pub reverse: bool,
+    pub ignore_fade: bool,
+    pub flags: Flags,
+    /// Sample pitch shifted to the voice's note, re-rendered when a legato note or the formant changes it
+    pub rendered: Option<Rendered>,
+    pub vibrato: Vibrato,
+    /// MIDI note that started this voice
+    pub note: u8,
+    pub glide: Glide,
+    pub pitch_curve: PitchCurve,
+    /// Crossfade gain, 1.0 once fully faded in
+    pub fade: f32,
+    /// Change in `fade` per sample, negative while fading out
+    pub fade_step: f32,
+    pub envelope: Adsr,
+    /// Position in the recorded tail after the loop, set while crossfading into it on release
+    pub tail: Option<isize>,
+    /// Progress of the crossfade into the tail, from 0.0 to 1.0
+    pub tail_fade: f32,
+    /// Expression of the imported note this voice sings
+    pub expression: NoteExpression,
+    /// Seconds since the voice started
+    pub age: f32,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -23,11 +68,184 @@ impl PlayingSample {
         Self {
             handle,
             position: 0,
+            phase: 0.0,
             gain,
             state: PlayingState::ATTACK,
             vowel_start: 0,
             vowel_end: 0,
+            sustain_loop: LoopPoints { start: 0, end: 0 },
+            sustain_mode: SustainMode::Loop,
+            stretch: None,
+            time_stretch: None,
+            frozen: None,
+            reverse: false,
             ignore_fade: true,
+            flags: Flags::default(),
+            rendered: None,
+            vibrato: Vibrato::new(),
+            note: 0,
+            glide: Glide::default(),
+            pitch_curve: PitchCurve::new(1),
+            fade: 1.0,
+            fade_step: 0.0,
+            envelope: Adsr::new(),
+            tail: None,
+            tail_fade: 0.0,
+            expression: NoteExpression::default(),
+            age: 0.0,
+        }
+    }
+
+    /// Starts the release, crossfading from the loop into the sample's tail if the vowel has started.
+    /// With `natural_tail` the tail plays out at its recorded level instead of following the envelope.
+    pub fn release(&mut self, natural_tail: bool, sample_rate: f32) {
+        self.state = PlayingState::RELEASE;
+        // the envelope holds until the note is let go, then its end section plays
+        if let Some(envelope) = self.expression.envelope {
+            self.expression.length = Some(self.age * 1000.0 + envelope.p[2] + envelope.p[3]);
+        }
+        self.ignore_fade = true;
+        self.reverse = false;
+        if self.position >= self.vowel_start as isize {
+            match self.frozen {
+                // a time-stretch has no loop, it stops repeating periods and plays on into the tail
+                _ if self.sustain_mode == SustainMode::Stretch => {}
+                // pick the recording back up a couple of periods after the frozen frame, in phase with it
+                Some(cycles) => {
+                    let tail = self.sustain_loop.start as f32
+                        + (cycles.fract() + 2.0) * self.wavelength(sample_rate);
+                    self.tail = Some(tail as isize);
+                    self.phase = tail.fract();
+                }
+                // the loop plays up to its end rounded to the rendered periods, so the tail has to pick up from there
+                None => {
+                    let loop_end = self
+                        .sustain_loop
+                        .end_for_wavelength(self.wavelength(sample_rate));
+                    self.tail = Some(loop_end as isize);
+                }
+            }
+            self.tail_fade = 0.0;
+            if natural_tail {
+                return;
+            }
+        }
+        self.envelope.release();
+    }
+
+    /// Moves the tail crossfade along by one output sample and the tail by `step` samples,
+    /// taking over from the loop once it's done
+    pub fn advance_tail(&mut self, crossfade_length: u32, step: isize) {
+        if let Some(tail) = self.tail {
+            self.tail_fade += 1.0 / crossfade_length.max(1) as f32;
+            if self.tail_fade >= 1.0 {
+                self.position = tail;
+                self.tail = None;
+                self.frozen = None;
+            } else {
+                self.tail = Some(tail + step);
+            }
+        }
+    }
+
+    /// Number of samples to move along for one output sample played at `rate` times the rendered pitch.
+    /// A voice which hasn't started yet counts down in output samples.
+    pub fn step(&mut self, rate: f32) -> isize {
+        if self.position < 0 {
+            return 1;
+        }
+        self.phase += rate.max(0.0);
+        let step = self.phase as isize;
+        self.phase -= step as f32;
+        step
+    }
+
+    /// Moves the playback position `step` samples along in the current direction
+    pub fn advance_position(&mut self, step: isize) {
+        self.position += if self.reverse { -step } else { step };
+    }
+
+    /// Moves the playback position back `distance` samples, keeping the fraction past it
+    pub fn jump_back(&mut self, distance: f32) {
+        let (index, phase) = self.behind(distance);
+        self.position = index;
+        self.phase = phase;
+    }
+
+    /// Playback position and fraction past it `distance` samples behind the current position
+    pub fn behind(&self, distance: f32) -> (isize, f32) {
+        let phase = self.phase - distance.fract();
+        let index = self.position - distance as isize;
+        if phase < 0.0 {
+            (index - 1, phase + 1.0)
+        } else {
+            (index, phase)
+        }
+    }
+
+    /// Pitch period of the rendered sample in samples, 0.0 before it's rendered
+    pub fn wavelength(&self, sample_rate: f32) -> f32 {
+        self.rendered
+            .as_ref()
+            .map_or(0.0, |rendered| sample_rate / rendered.target_frequency)
+    }
+
+    /// Length of the voice's rendered sample, which is longer than the recording when stretched
+    pub fn length(&self, sample_length: usize) -> isize {
+        match &self.stretch {
+            Some(stretch) => stretch.map(sample_length as u32) as isize,
+            None => sample_length as isize,
+        }
+    }
+
+    /// Whether the voice is still held and not on its way out
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, PlayingState::ATTACK | PlayingState::SUSTAIN) && self.fade_step >= 0.0
+    }
+
+    pub fn fade_in(&mut self, length: f32) {
+        if length >= 1.0 {
+            self.fade = 0.0;
+            self.fade_step = 1.0 / length;
         }
     }
+
+    pub fn fade_out(&mut self, length: f32) {
+        self.fade_step = -1.0 / length.max(1.0);
+    }
+
+    pub fn advance_fade(&mut self) {
+        self.fade = (self.fade + self.fade_step).clamp(0.0, 1.0);
+    }
+
+    pub fn is_faded_out(&self) -> bool {
+        self.fade_step < 0.0 && self.fade <= 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tail_starts_at_the_rounded_loop_end() {
+        let mut voice = PlayingSample::new(PathBuf::new(), 1.0);
+        voice.vowel_start = 1000;
+        voice.position = 2000;
+        voice.sustain_loop = LoopPoints {
+            start: 1000,
+            end: 3000,
+        };
+        voice.rendered = Some(Rendered {
+            samples: vec![],
+            breath: vec![],
+            frozen: vec![],
+            frozen_breath: vec![],
+            target_frequency: 300.0,
+            formant_shift: 0.0,
+        });
+        voice.release(true, 44100.0);
+        // 2000 samples round to 14 periods of 147 samples
+        assert_eq!(voice.tail, Some(1000 + 14 * 147));
+    }
 }
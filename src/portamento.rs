@@ -0,0 +1,94 @@
+use nih_plug::prelude::Enum;
+
+/// What happens when a note starts while another one is still held
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceMode {
+    /// Glide to the new note and crossfade into its lyric without restarting the voice
+    Legato,
+    /// Release the held note and start the new one from scratch
+    Retrigger,
+}
+
+/// Pitch glide between notes, in semitones
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Glide {
+    from: f32,
+    to: f32,
+    /// Length of the glide in seconds
+    time: f32,
+    elapsed: f32,
+}
+
+impl Glide {
+    pub fn new(note: f32) -> Self {
+        Self {
+            from: note,
+            to: note,
+            time: 0.0,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn current(&self) -> f32 {
+        if self.elapsed >= self.time {
+            return self.to;
+        }
+        self.from + (self.to - self.from) * (self.elapsed / self.time)
+    }
+
+    pub fn target(&self) -> f32 {
+        self.to
+    }
+
+    /// Starts gliding from the current pitch to `note` over `time` seconds
+    pub fn glide_to(&mut self, note: f32, time: f32) {
+        self.from = self.current();
+        self.to = note;
+        self.time = time;
+        self.elapsed = 0.0;
+    }
+
+    pub fn advance(&mut self, seconds: f32) {
+        self.elapsed += seconds;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glide_reaches_the_note_in_its_time() {
+        let mut glide = Glide::new(60.0);
+        glide.glide_to(64.0, 0.1);
+        assert_eq!(glide.current(), 60.0);
+        assert_eq!(glide.target(), 64.0);
+        glide.advance(0.05);
+        assert!((glide.current() - 62.0).abs() < 1e-4);
+        glide.advance(0.05);
+        assert_eq!(glide.current(), 64.0);
+        glide.advance(1.0);
+        assert_eq!(glide.current(), 64.0);
+    }
+
+    #[test]
+    fn glide_can_change_target_halfway() {
+        let mut glide = Glide::new(60.0);
+        glide.glide_to(64.0, 0.1);
+        glide.advance(0.05);
+        // a new note glides on from where the pitch is now
+        glide.glide_to(58.0, 0.2);
+        assert!((glide.current() - 62.0).abs() < 1e-4);
+        glide.advance(0.1);
+        assert!((glide.current() - 60.0).abs() < 1e-4);
+        glide.advance(0.1);
+        assert_eq!(glide.current(), 58.0);
+    }
+
+    #[test]
+    fn glide_without_time_jumps() {
+        let mut glide = Glide::new(60.0);
+        glide.glide_to(67.0, 0.0);
+        assert_eq!(glide.current(), 67.0);
+    }
+}
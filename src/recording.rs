@@ -0,0 +1,438 @@
+use crate::{
+    expression::{BendPoint, BendShape, NoteExpression, PitchBend},
+    ust::{Ust, UstNote, TICKS_PER_BEAT},
+    ustx::{
+        UstxNote, UstxPart, UstxPitch, UstxPitchPoint, UstxProject, UstxTempo, UstxTrack,
+        UstxVibrato,
+    },
+};
+
+/// A note played while recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedNote {
+    /// Start in beats from the start of the recording
+    pub beat: f64,
+    /// Length in beats, `None` while the note is held
+    pub length: Option<f64>,
+    pub note: u8,
+    /// MIDI velocity, from 0.0 to 1.0
+    pub velocity: f32,
+    /// UTAU consonant velocity the note was sung with
+    pub consonant_velocity: f32,
+    pub lyric: String,
+    /// Pitch bend while the note was held, in milliseconds from its start and cents
+    pub pitch_bend: Vec<(f32, f32)>,
+}
+
+/// Notes captured in Record mode, for exporting as an UTAU or OpenUtau project
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub notes: Vec<RecordedNote>,
+    pub tempo: f64,
+    /// Beat the recording starts at, the song's start when the transport was playing
+    origin: Option<f64>,
+}
+
+impl Recording {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.is_empty()
+    }
+
+    /// Adds a note, with its `beat` on the host's timeline. The first note sets the origin:
+    /// the start of the song when it's played from the host's timeline, otherwise the beat the note falls in.
+    pub fn note_on(&mut self, mut note: RecordedNote, from_song: bool, tempo: f64) {
+        let origin = *self.origin.get_or_insert_with(|| {
+            self.tempo = tempo;
+            if from_song {
+                0.0
+            } else {
+                note.beat.floor()
+            }
+        });
+        note.beat = (note.beat - origin).max(0.0);
+        self.notes.push(note);
+    }
+
+    pub fn note_off(&mut self, beat: f64, note: u8) {
+        let origin = self.origin.unwrap_or(0.0);
+        if let Some(held) = self.held(note) {
+            held.length = Some((beat - origin - held.beat).max(0.0));
+        }
+    }
+
+    /// Changes the lyric of the held `note`, for a lyric which arrived just after it
+    pub fn set_lyric(&mut self, note: u8, lyric: &str) {
+        if let Some(held) = self.held(note) {
+            held.lyric = lyric.to_string();
+        }
+    }
+
+    /// The last recorded `note` which is still held
+    fn held(&mut self, note: u8) -> Option<&mut RecordedNote> {
+        self.notes
+            .iter_mut()
+            .rev()
+            .find(|held| held.note == note && held.length.is_none())
+    }
+
+    /// Adds a pitch bend point in cents to the held notes
+    pub fn pitch_bend(&mut self, beat: f64, cents: f32) {
+        let origin = self.origin.unwrap_or(0.0);
+        let ms_per_beat = 60_000.0 / self.tempo.max(1.0);
+        for held in self.notes.iter_mut().filter(|held| held.length.is_none()) {
+            let time = ((beat - origin - held.beat) * ms_per_beat) as f32;
+            // the note started unbent
+            if held.pitch_bend.is_empty() && time > 0.0 {
+                held.pitch_bend.push((0.0, 0.0));
+            }
+            held.pitch_bend.push((time.max(0.0), cents));
+        }
+    }
+
+    /// Notes in ticks, each cut off where the next one starts, as UTAU sequences can't overlap.
+    /// Notes still held end at `end`.
+    fn sequenced(&self, end: f64) -> Vec<(u32, u32, &RecordedNote)> {
+        let ticks = |beat: f64| (beat * TICKS_PER_BEAT as f64).round().max(0.0) as u32;
+        let mut notes: Vec<&RecordedNote> = self.notes.iter().collect();
+        notes.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+        let mut sequenced = vec![];
+        for (i, note) in notes.iter().enumerate() {
+            let start = ticks(note.beat);
+            let mut stop = ticks(note.length.map_or(end, |length| note.beat + length));
+            if let Some(next) = notes.get(i + 1) {
+                stop = stop.min(ticks(next.beat));
+            }
+            if stop > start {
+                sequenced.push((start, stop - start, *note));
+            }
+        }
+        sequenced
+    }
+
+    fn end(&self) -> f64 {
+        self.notes
+            .iter()
+            .map(|note| note.beat + note.length.unwrap_or(0.0))
+            .fold(0.0, f64::max)
+    }
+
+    fn pitch_bend_of(note: &RecordedNote) -> Option<PitchBend> {
+        (!note.pitch_bend.is_empty()).then(|| PitchBend {
+            points: note
+                .pitch_bend
+                .iter()
+                .map(|(time, cents)| BendPoint {
+                    time: *time,
+                    cents: *cents,
+                    shape: BendShape::Linear,
+                })
+                .collect(),
+        })
+    }
+
+    /// The recording as an UTAU sequence, with rests filling the gaps between notes
+    pub fn to_ust(&self, project_name: &str, voice_dir: &str) -> Ust {
+        let mut notes = vec![];
+        let mut position = 0;
+        for (start, length, note) in self.sequenced(self.end()) {
+            if start > position {
+                notes.push(UstNote {
+                    position,
+                    length: start - position,
+                    note_num: note.note,
+                    lyric: String::from("R"),
+                    ..Default::default()
+                });
+            }
+            notes.push(UstNote {
+                position: start,
+                length,
+                note_num: note.note,
+                lyric: note.lyric.clone(),
+                flags: String::new(),
+                expression: NoteExpression {
+                    velocity: Some(note.consonant_velocity),
+                    intensity: Some(note.velocity * 100.0),
+                    pitch_bend: Self::pitch_bend_of(note),
+                    ..Default::default()
+                },
+            });
+            position = start + length;
+        }
+        Ust {
+            tempo: self.tempo,
+            project_name: project_name.to_string(),
+            voice_dir: voice_dir.to_string(),
+            flags: String::new(),
+            notes,
+        }
+    }
+
+    /// The recording as an OpenUtau project with a single part
+    pub fn to_ustx(&self, project_name: &str, singer: &str) -> UstxProject {
+        let notes: Vec<UstxNote> = self
+            .sequenced(self.end())
+            .into_iter()
+            .map(|(start, length, note)| UstxNote {
+                position: start as i64,
+                duration: length as i64,
+                tone: note.note as i32,
+                lyric: note.lyric.clone(),
+                pitch: UstxPitch {
+                    data: Self::pitch_bend_of(note)
+                        .map_or_else(default_pitch, |bend| bend.points)
+                        .iter()
+                        .map(|point| UstxPitchPoint {
+                            x: point.time,
+                            y: point.cents / 10.0,
+                            shape: point.shape.to_ustx().to_string(),
+                        })
+                        .collect(),
+                    snap_first: true,
+                },
+                vibrato: UstxVibrato::default(),
+                phoneme_overrides: vec![],
+            })
+            .collect();
+        let duration = notes.last().map_or(0, |note| note.position + note.duration);
+        UstxProject {
+            name: project_name.to_string(),
+            bpm: self.tempo,
+            tempos: vec![UstxTempo {
+                position: 0,
+                bpm: self.tempo,
+            }],
+            tracks: vec![UstxTrack {
+                singer: singer.to_string(),
+                phonemizer: String::from("OpenUtau.Core.DefaultPhonemizer"),
+            }],
+            voice_parts: vec![UstxPart {
+                name: project_name.to_string(),
+                track_no: 0,
+                position: 0,
+                duration,
+                notes,
+                curves: vec![],
+            }],
+            ..Default::default()
+        }
+    }
+}
+
+/// The two flat points OpenUtau gives a new note
+fn default_pitch() -> Vec<BendPoint> {
+    [-25.0, 25.0]
+        .into_iter()
+        .map(|time| BendPoint {
+            time,
+            cents: 0.0,
+            shape: BendShape::InOut,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPO: f64 = 120.0;
+
+    fn note(beat: f64, note: u8, lyric: &str) -> RecordedNote {
+        RecordedNote {
+            beat,
+            length: None,
+            note,
+            velocity: 0.5,
+            consonant_velocity: 100.0,
+            lyric: lyric.to_string(),
+            pitch_bend: vec![],
+        }
+    }
+
+    /// Records notes given as start and end beats, note and lyric, played from the song
+    fn recording(notes: &[(f64, f64, u8, &str)]) -> Recording {
+        let mut recording = Recording::new();
+        for &(start, end, key, lyric) in notes {
+            recording.note_on(note(start, key, lyric), true, TEMPO);
+            recording.note_off(end, key);
+        }
+        recording
+    }
+
+    fn sequence(ust: &Ust) -> Vec<(u32, u32, u8, &str)> {
+        ust.notes
+            .iter()
+            .map(|note| {
+                let lyric = note.lyric.as_str();
+                (note.position, note.length, note.note_num, lyric)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn notes_are_measured_from_the_origin() {
+        // played without the song, the recording starts at the beat of the first note
+        let mut recording = Recording::new();
+        recording.note_on(note(4.5, 60, "あ"), false, TEMPO);
+        recording.note_off(6.0, 60);
+        recording.note_on(note(7.0, 62, "か"), false, 90.0);
+        assert_eq!(recording.tempo, TEMPO);
+        assert_eq!(recording.notes[0].beat, 0.5);
+        assert_eq!(recording.notes[0].length, Some(1.5));
+        assert_eq!(recording.notes[1].beat, 3.0);
+        assert_eq!(recording.notes[1].length, None);
+
+        // played from the song, it starts at the start of the song
+        let mut recording = Recording::new();
+        recording.note_on(note(4.5, 60, "あ"), true, TEMPO);
+        assert_eq!(recording.notes[0].beat, 4.5);
+    }
+
+    #[test]
+    fn note_off_ends_the_last_held_note() {
+        let mut recording = recording(&[(0.0, 1.0, 60, "あ")]);
+        recording.note_on(note(2.0, 60, "か"), true, TEMPO);
+        recording.note_off(3.0, 62);
+        assert_eq!(recording.notes[1].length, None);
+        recording.note_off(3.5, 60);
+        assert_eq!(recording.notes[0].length, Some(1.0));
+        assert_eq!(recording.notes[1].length, Some(1.5));
+        // already released
+        recording.note_off(5.0, 60);
+        assert_eq!(recording.notes[1].length, Some(1.5));
+    }
+
+    #[test]
+    fn late_lyric_changes_the_held_note() {
+        let mut recording = recording(&[(0.0, 1.0, 60, "あ")]);
+        recording.note_on(note(1.0, 60, "あ"), true, TEMPO);
+        recording.set_lyric(60, "か");
+        recording.set_lyric(62, "さ");
+        assert_eq!(recording.notes[0].lyric, "あ");
+        assert_eq!(recording.notes[1].lyric, "か");
+    }
+
+    #[test]
+    fn pitch_bend_follows_held_notes() {
+        let mut recording = recording(&[(0.0, 1.0, 60, "あ")]);
+        recording.note_on(note(1.0, 62, "か"), true, TEMPO);
+        // half a beat is 250 ms at 120 BPM
+        recording.pitch_bend(1.5, 50.0);
+        recording.pitch_bend(2.0, 0.0);
+        assert!(recording.notes[0].pitch_bend.is_empty());
+        assert_eq!(
+            recording.notes[1].pitch_bend,
+            [(0.0, 0.0), (250.0, 50.0), (500.0, 0.0)]
+        );
+
+        // a bend as the note starts doesn't add the unbent start
+        recording.note_on(note(3.0, 64, "さ"), true, TEMPO);
+        recording.note_off(2.5, 62);
+        recording.pitch_bend(3.0, -100.0);
+        assert_eq!(recording.notes[2].pitch_bend, [(0.0, -100.0)]);
+    }
+
+    #[test]
+    fn overlapping_notes_are_cut_off_and_gaps_are_rests() {
+        let recording = recording(&[
+            (1.0, 3.0, 60, "あ"),
+            (2.0, 4.0, 62, "か"),
+            (5.0, 6.0, 64, "さ"),
+        ]);
+        let ust = recording.to_ust("song", "voice");
+        assert_eq!(
+            sequence(&ust),
+            [
+                (0, 480, 60, "R"),
+                (480, 480, 60, "あ"),
+                (960, 960, 62, "か"),
+                (1920, 480, 64, "R"),
+                (2400, 480, 64, "さ"),
+            ]
+        );
+        assert!(ust.notes[0].is_rest());
+    }
+
+    #[test]
+    fn held_notes_end_with_the_recording() {
+        let mut recording = recording(&[(0.0, 1.0, 60, "あ"), (1.0, 4.0, 62, "か")]);
+        recording.note_on(note(2.0, 64, "さ"), true, TEMPO);
+        let ust = recording.to_ust("song", "voice");
+        assert_eq!(
+            sequence(&ust),
+            [
+                (0, 480, 60, "あ"),
+                (480, 480, 62, "か"),
+                (960, 960, 64, "さ")
+            ]
+        );
+    }
+
+    #[test]
+    fn ust_reads_back() {
+        let mut recording = recording(&[(0.0, 1.0, 60, "あ")]);
+        recording.note_on(note(2.0, 62, "きゃ"), true, TEMPO);
+        recording.pitch_bend(2.5, 100.0);
+        recording.note_off(3.0, 62);
+
+        let ust = Ust::parse(&recording.to_ust("song", "voice").to_string());
+        assert_eq!(ust.tempo, TEMPO);
+        assert_eq!(ust.project_name, "song");
+        assert_eq!(ust.voice_dir, "voice");
+        assert_eq!(
+            sequence(&ust),
+            [
+                (0, 480, 60, "あ"),
+                (480, 480, 62, "R"),
+                (960, 480, 62, "きゃ")
+            ]
+        );
+        let expression = &ust.notes[2].expression;
+        assert_eq!(expression.intensity, Some(50.0));
+        assert_eq!(expression.velocity, Some(100.0));
+        let bend = expression.pitch_bend.as_ref().unwrap();
+        let points: Vec<(f32, f32)> = bend
+            .points
+            .iter()
+            .map(|point| (point.time, point.cents))
+            .collect();
+        assert_eq!(points, [(0.0, 0.0), (250.0, 100.0)]);
+    }
+
+    #[test]
+    fn ustx_reads_back() {
+        let mut recording = recording(&[(0.0, 1.0, 60, "あ")]);
+        recording.note_on(note(2.0, 62, "か"), true, TEMPO);
+        recording.pitch_bend(2.5, 100.0);
+        recording.note_off(3.0, 62);
+
+        let text = serde_yaml::to_string(&recording.to_ustx("song", "singer")).unwrap();
+        let project: UstxProject = serde_yaml::from_str(&text).unwrap();
+        assert_eq!(project.name, "song");
+        assert_eq!(project.tracks(), [0]);
+        assert_eq!(project.voice_parts[0].duration, 1440);
+        let timeline = project.timeline(0);
+        let lyrics: Vec<(f64, &str)> = timeline
+            .entries()
+            .iter()
+            .map(|entry| (entry.beat, entry.lyric.as_str()))
+            .collect();
+        assert_eq!(lyrics, [(0.0, "あ"), (2.0, "か")]);
+
+        let pitch = |index: usize| {
+            project.voice_parts[0].notes[index]
+                .pitch
+                .data
+                .iter()
+                .map(|point| (point.x, point.y))
+                .collect::<Vec<_>>()
+        };
+        // an unbent note gets OpenUtau's flat default
+        assert_eq!(pitch(0), [(-25.0, 0.0), (25.0, 0.0)]);
+        assert_eq!(pitch(1), [(0.0, 0.0), (250.0, 10.0)]);
+    }
+}
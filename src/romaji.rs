@@ -0,0 +1,242 @@
+use crate::kana;
+
+/// Kunrei spellings read ahead of `kana::MORAS`, where `tyu` is the てゅ of UTAU romaji banks.
+/// Typed lyrics are more likely to mean ちゅ, and てゅ can still be typed as `thu`.
+const KUNREI: &[(&str, &str)] = &[("tya", "ちゃ"), ("tyu", "ちゅ"), ("tyo", "ちょ")];
+
+/// Other spellings accepted on input, which are never written out. Romaji is read and written with the
+/// Hepburn spellings of `kana::MORAS`, where `chi` and `tsu` are ち and つ and `ti` and `tu` are てぃ and とぅ.
+/// Kunrei-shiki `ti` and `tu` would leave those without a spelling in a romaji bank, so Kunrei users
+/// type ち and つ as `chi` and `tsu`, and the rest of Kunrei-shiki is taken where it doesn't clash.
+const SPELLINGS: &[(&str, &str)] = &[
+    ("si", "し"),
+    ("zi", "じ"),
+    ("hu", "ふ"),
+    ("sya", "しゃ"),
+    ("syu", "しゅ"),
+    ("sye", "しぇ"),
+    ("syo", "しょ"),
+    ("zya", "じゃ"),
+    ("zyu", "じゅ"),
+    ("zye", "じぇ"),
+    ("zyo", "じょ"),
+    ("jya", "じゃ"),
+    ("jyu", "じゅ"),
+    ("jye", "じぇ"),
+    ("jyo", "じょ"),
+    ("thi", "てぃ"),
+    ("t'i", "てぃ"),
+    ("thu", "てゅ"),
+    ("twu", "とぅ"),
+    ("t'u", "とぅ"),
+    ("dhi", "でぃ"),
+    ("d'i", "でぃ"),
+    ("dhu", "でゅ"),
+    ("dwu", "どぅ"),
+    ("d'u", "どぅ"),
+    ("ltsu", "っ"),
+    ("xtu", "っ"),
+    ("ltu", "っ"),
+];
+
+/// Hepburn long vowels, written out as the kana they stand for
+const LONG_VOWELS: &[(char, &str)] = &[
+    ('ā', "aa"),
+    ('ī', "ii"),
+    ('ū', "uu"),
+    ('ē', "ee"),
+    ('ō', "ou"),
+    ('â', "aa"),
+    ('î', "ii"),
+    ('û', "uu"),
+    ('ê', "ee"),
+    ('ô', "ou"),
+];
+
+fn is_vowel(c: char) -> bool {
+    matches!(c, 'a' | 'i' | 'u' | 'e' | 'o')
+}
+
+fn find_spelling(spellings: &[(&str, &'static str)], romaji: &str) -> Option<&'static str> {
+    spellings
+        .iter()
+        .find(|(spelling, _)| *spelling == romaji)
+        .map(|(_, kana)| *kana)
+}
+
+fn lookup(romaji: &str) -> Option<&'static str> {
+    find_spelling(KUNREI, romaji)
+        .or_else(|| kana::find_romaji(romaji).map(|mora| mora.hiragana))
+        .or_else(|| find_spelling(SPELLINGS, romaji))
+}
+
+/// Converts Hepburn or Kunrei romaji, as described on `SPELLINGS`, to hiragana. Kana and anything that isn't romaji are kept as they are.
+/// `n` before a consonant or at the end is ん, and `n'` separates it from a following vowel.
+/// Doubled consonants and `tch` start with っ.
+pub fn to_kana(text: &str) -> String {
+    let mut romaji = String::new();
+    for c in text.chars() {
+        match LONG_VOWELS
+            .iter()
+            .find(|(long, _)| c.to_lowercase().eq([*long]))
+        {
+            Some((_, vowels)) => romaji.push_str(vowels),
+            None => romaji.push(c),
+        }
+    }
+    let chars: Vec<char> = romaji.chars().collect();
+    let lower: Vec<char> = chars.iter().map(|c| c.to_ascii_lowercase()).collect();
+
+    let mut kana = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = lower[i];
+        let next = lower.get(i + 1).copied();
+        if !c.is_ascii_alphabetic() {
+            kana.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        if c == 'n' {
+            let starts_syllable = |c: Option<&char>| c.is_some_and(|c| is_vowel(*c) || *c == 'y');
+            // `n'` and a doubled `n` are a single ん, unless the second n starts a syllable like in `onna`
+            if next == Some('\'') || (next == Some('n') && !starts_syllable(lower.get(i + 2))) {
+                kana.push('ん');
+                i += 2;
+                continue;
+            }
+            if !starts_syllable(next.as_ref()) {
+                kana.push('ん');
+                i += 1;
+                continue;
+            }
+        }
+        if !is_vowel(c) && (next == Some(c) || (c == 't' && next == Some('c'))) {
+            kana.push('っ');
+            i += 1;
+            continue;
+        }
+        let matched = (1..=4.min(lower.len() - i)).rev().find_map(|length| {
+            let syllable: String = lower[i..i + length].iter().collect();
+            lookup(&syllable).map(|kana| (kana, length))
+        });
+        match matched {
+            Some((syllable, length)) => {
+                kana.push_str(syllable);
+                i += length;
+            }
+            None => {
+                kana.push(chars[i]);
+                i += 1;
+            }
+        }
+    }
+    kana
+}
+
+/// Converts hiragana or katakana to Hepburn romaji, as UTAU romaji banks name their aliases.
+/// っ doubles the next consonant, ー repeats the last vowel and anything else is kept as it is.
+pub fn to_romaji(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut romaji = String::new();
+    let mut geminate = false;
+    let mut after_n = false;
+    let mut i = 0;
+    while i < chars.len() {
+        // small kana like the ゃ of きゃ belong to the mora before them
+        let matched = (1..=2.min(chars.len() - i)).rev().find_map(|length| {
+            let text: String = chars[i..i + length].iter().collect();
+            kana::find(&text).map(|mora| (mora, length))
+        });
+        let Some((mora, length)) = matched else {
+            after_n = false;
+            if chars[i] == 'ー' {
+                if let Some(vowel) = romaji.chars().last().filter(|c| is_vowel(*c)) {
+                    romaji.push(vowel);
+                }
+            } else {
+                romaji.push(chars[i]);
+            }
+            i += 1;
+            continue;
+        };
+        i += length;
+
+        if mora.vowel.is_none() && i < chars.len() {
+            geminate = true;
+            continue;
+        }
+        if geminate {
+            geminate = false;
+            match mora.romaji {
+                syllable if syllable.starts_with("ch") => romaji.push('t'),
+                syllable => romaji.extend(syllable.chars().next().filter(|c| !is_vowel(*c))),
+            }
+        }
+        // ん before a vowel or y would read as part of the next syllable
+        if after_n && mora.romaji.starts_with(|c: char| is_vowel(c) || c == 'y') {
+            romaji.push('\'');
+        }
+        after_n = mora.romaji == "n";
+        romaji.push_str(mora.romaji);
+    }
+    romaji
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_mora_round_trips() {
+        for mora in kana::MORAS {
+            // moras which share a spelling, like じ and ぢ, come back as the first of them
+            let canonical = kana::find_romaji(mora.romaji).unwrap();
+            assert_eq!(to_romaji(mora.hiragana), mora.romaji, "{}", mora.hiragana);
+            assert_eq!(to_romaji(mora.katakana), mora.romaji, "{}", mora.katakana);
+            if find_spelling(KUNREI, mora.romaji).is_none() {
+                assert_eq!(to_kana(mora.romaji), canonical.hiragana, "{}", mora.romaji);
+            }
+        }
+    }
+
+    #[test]
+    fn other_spellings_dont_clash_with_the_table() {
+        for (spelling, kana) in SPELLINGS {
+            assert!(kana::find_romaji(spelling).is_none(), "{spelling}");
+            assert_eq!(to_kana(spelling), *kana);
+        }
+    }
+
+    #[test]
+    fn hepburn_and_kunrei() {
+        assert_eq!(to_kana("chi tsu ti tu"), "ち つ てぃ とぅ");
+        assert_eq!(to_kana("si zi hu sya"), "し じ ふ しゃ");
+        assert_eq!(to_romaji("ちつてぃとぅ"), "chitsutitu");
+        assert_eq!(to_kana("tya tyu tyo thu"), "ちゃ ちゅ ちょ てゅ");
+        assert_eq!(to_romaji("てゅ"), "tyu");
+    }
+
+    #[test]
+    fn n_and_doubled_consonants() {
+        assert_eq!(to_kana("konnichiwa"), "こんにちわ");
+        assert_eq!(to_kana("onna"), "おんな");
+        assert_eq!(to_kana("kon'ya"), "こんや");
+        assert_eq!(to_kana("hon"), "ほん");
+        assert_eq!(to_kana("kitte matcha"), "きって まっちゃ");
+        assert_eq!(to_romaji("こんや"), "kon'ya");
+        assert_eq!(to_romaji("きって"), "kitte");
+        assert_eq!(to_romaji("まっちゃ"), "matcha");
+        assert_eq!(to_romaji("っ"), "xtsu");
+    }
+
+    #[test]
+    fn long_vowels_and_other_text() {
+        assert_eq!(to_kana("tōkyō"), "とうきょう");
+        assert_eq!(to_kana("Sakura"), "さくら");
+        assert_eq!(to_kana("Ōsaka Ākita Âme"), "おうさか ああきた ああめ");
+        assert_eq!(to_kana("a か 息"), "あ か 息");
+        assert_eq!(to_romaji("らーめん"), "raamen");
+        assert_eq!(to_romaji("a か 息"), "a ka 息");
+    }
+}
@@ -1,5 +1,6 @@
 use crate::LoadedSample;
 use rubato::Resampler;
+use std::collections::HashMap;
 
 const DEFAULT_FREQUENCY: f32 = 440.0; //middle c
 
@@ -25,6 +26,7 @@ pub fn uninterleave(samples: Vec<f32>, channels: usize) -> LoadedSample {
     LoadedSample {
         samples: new_samples,
         frequency: DEFAULT_FREQUENCY,
+        loops: HashMap::new(),
     }
 }
 
@@ -53,11 +55,13 @@ pub fn resample(samples: LoadedSample, sample_rate_in: f32, sample_rate_out: f32
             LoadedSample {
                 samples: waves_out,
                 frequency: samples.frequency,
+                loops: samples.loops,
             }
         }
         Err(_) => LoadedSample {
             samples: vec![],
             frequency: DEFAULT_FREQUENCY,
+            loops: HashMap::new(),
         },
     }
 }
@@ -0,0 +1,540 @@
+use nih_plug::prelude::Enum;
+
+/// How a held note carries on past the end of the recorded vowel
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SustainMode {
+    /// Crossfade loop between the loop points
+    #[name = "Loop"]
+    Loop,
+    /// Plays the loop forwards and backwards
+    #[name = "Ping-Pong"]
+    PingPong,
+    /// Slows the vowel down with TD-PSOLA for as long as the note is held, without a loop point
+    #[name = "Time-Stretch"]
+    Stretch,
+    /// Holds the spectrum of the most stable frame near the end of the vowel
+    #[name = "Freeze"]
+    Freeze,
+}
+
+/// Loop points for the sustained vowel, found when the singer is loaded.
+/// Playback jumps from `end` back to `start`, crossfading the samples before `end`
+/// with the samples before `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopPoints {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl LoopPoints {
+    pub fn length(&self) -> u32 {
+        self.end.saturating_sub(self.start)
+    }
+
+    /// End of the loop rounded so the loop holds a whole number of `wavelength`s.
+    /// The rendered sample has the target pitch, so the loop has to be rounded to its periods
+    /// rather than the periods of the recording.
+    pub fn end_for_wavelength(&self, wavelength: f32) -> u32 {
+        if wavelength < 1.0 {
+            return self.end;
+        }
+        let periods = (self.length() as f32 / wavelength).round().max(1.0);
+        self.start + (periods * wavelength).round() as u32
+    }
+}
+
+/// Loop points of an oto entry for each sustain mode which loops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SustainLoops {
+    pub sustain: LoopPoints,
+    pub freeze: LoopPoints,
+}
+
+/// How much slower the vowel starts playing in the time-stretch sustain mode
+pub const STRETCH_FACTOR: f32 = 4.0;
+
+/// TD-PSOLA time-stretch of the sustained vowel, for the time-stretch sustain mode.
+/// The vowel plays a pitch period at a time, and each period either moves on
+/// or crossfades back into a repeat of itself, so there is no loop point.
+/// It starts at `1 / STRETCH_FACTOR` of the recorded speed and keeps slowing down,
+/// only reaching the end of the vowel if the note is held forever.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeStretch {
+    /// Length of the vowel left when the stretch started
+    length: f32,
+    /// How far into the vowel the periods played so far have moved on
+    progress: f32,
+    /// How long the stretch has played for, in samples of the rendered vowel
+    elapsed: f32,
+    /// How far playback is into the current period
+    offset: f32,
+    /// Whether the current period crossfades back into a repeat of itself
+    repeat: bool,
+}
+
+impl TimeStretch {
+    pub fn new(length: f32) -> Self {
+        Self {
+            length: length.max(0.0),
+            progress: 0.0,
+            elapsed: 0.0,
+            offset: 0.0,
+            repeat: false,
+        }
+    }
+
+    /// Gain of the read a period behind the playback position, which fades in while a period repeats.
+    /// The playback position fades out by the rest, the two halves of the Hann windows sum to 1.0.
+    pub fn repeat_gain(&self, wavelength: f32) -> f32 {
+        if !self.repeat || wavelength <= 0.0 {
+            return 0.0;
+        }
+        (self.offset / wavelength * std::f32::consts::FRAC_PI_2)
+            .sin()
+            .powi(2)
+    }
+
+    /// Moves `step` samples on, returning true when a repeated period ends and playback jumps back a period.
+    /// No more periods repeat once `hold` is false, so a released note plays on into its tail.
+    pub fn advance(&mut self, step: f32, wavelength: f32, hold: bool) -> bool {
+        self.elapsed += step;
+        self.offset += step;
+        if self.offset < wavelength {
+            return false;
+        }
+        self.offset -= wavelength;
+        let jump = self.repeat;
+        if !jump {
+            self.progress += wavelength;
+        }
+        // repeat the next period if moving on would get ahead of the stretch,
+        // its crossfade reads up to a period past the one it moves on to
+        self.repeat =
+            hold && self.progress + wavelength * 2.0 > self.target(self.elapsed + wavelength);
+        jump
+    }
+
+    /// How far into the vowel the stretch should be after playing for `elapsed` samples
+    fn target(&self, elapsed: f32) -> f32 {
+        if self.length <= 0.0 {
+            return 0.0;
+        }
+        self.length * (1.0 - (-elapsed / (self.length * STRETCH_FACTOR)).exp())
+    }
+}
+
+/// Number of samples the frozen period of the freeze sustain mode is resynthesised into
+pub const FROZEN_LENGTH: usize = 2048;
+
+/// Spectral freeze of the frame of two pitch periods starting at `start`.
+/// The level and phase of each harmonic in the Hann windowed frame are measured,
+/// then resynthesised into one period of `FROZEN_LENGTH` samples, which repeats without a seam.
+/// Sample `i` of the period lines up with `start + i * wavelength / FROZEN_LENGTH` of `samples`.
+pub fn freeze_frame(samples: &[f32], start: usize, wavelength: f32) -> Vec<f32> {
+    let mut period = vec![0.0; FROZEN_LENGTH];
+    let length = (wavelength * 2.0).round() as usize;
+    if !wavelength.is_finite() || wavelength < 2.0 || start + length > samples.len() {
+        return period;
+    }
+    let frame: Vec<f32> = samples[start..start + length]
+        .iter()
+        .enumerate()
+        .map(|(n, sample)| {
+            let window = 0.5 - 0.5 * (std::f32::consts::TAU * n as f32 / length as f32).cos();
+            sample * window
+        })
+        .collect();
+    // a windowed harmonic of level 1.0 measures half the window's sum
+    let scale = 4.0 / length as f32;
+
+    let cosine: Vec<f32> = (0..FROZEN_LENGTH)
+        .map(|i| (std::f32::consts::TAU * i as f32 / FROZEN_LENGTH as f32).cos())
+        .collect();
+    // every harmonic below the Nyquist frequency
+    let harmonics = ((wavelength / 2.0).ceil() as usize - 1).min(FROZEN_LENGTH / 2 - 1);
+    for harmonic in 1..=harmonics {
+        // turn a phasor round at the harmonic's frequency rather than calling sin and cos every sample
+        let angle = std::f64::consts::TAU * harmonic as f64 / wavelength as f64;
+        let (step_re, step_im) = (angle.cos(), -angle.sin());
+        let (mut re, mut im) = (0.0_f64, 0.0_f64);
+        let (mut phasor_re, mut phasor_im) = (1.0_f64, 0.0_f64);
+        for sample in &frame {
+            re += *sample as f64 * phasor_re;
+            im += *sample as f64 * phasor_im;
+            (phasor_re, phasor_im) = (
+                phasor_re * step_re - phasor_im * step_im,
+                phasor_re * step_im + phasor_im * step_re,
+            );
+        }
+        let (re, im) = (re as f32 * scale, im as f32 * scale);
+        // level * cos(x + phase) = re * cos(x) - im * sin(x)
+        for (i, sample) in period.iter_mut().enumerate() {
+            let index = harmonic * i % FROZEN_LENGTH;
+            let sine = cosine[(index + FROZEN_LENGTH * 3 / 4) % FROZEN_LENGTH];
+            *sample += re * cosine[index] - im * sine;
+        }
+    }
+    period
+}
+
+/// Stretch factor of the consonant for a UTAU consonant velocity from 0 to 200.
+/// 100 keeps the recorded length, every 100 above halves it and every 100 below doubles it.
+pub fn consonant_factor(velocity: f32) -> f32 {
+    2.0_f32.powf((100.0 - velocity) / 100.0)
+}
+
+/// A part of the recording played `factor` times slower than it was recorded,
+/// like the consonant from the oto offset to the consonant marker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StretchRegion {
+    pub start: u32,
+    pub end: u32,
+    pub factor: f32,
+}
+
+impl StretchRegion {
+    pub fn length(&self) -> u32 {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn stretched_length(&self) -> u32 {
+        (self.length() as f32 * self.factor) as u32
+    }
+
+    /// Maps a position in the recording to the stretched playback position
+    pub fn map(&self, position: u32) -> u32 {
+        if position <= self.start {
+            return position;
+        }
+        if position <= self.end {
+            return self.start + ((position - self.start) as f32 * self.factor) as u32;
+        }
+        // everything after the region moves by how much longer it has become, or shorter
+        (position as i64 + self.stretched_length() as i64 - self.length() as i64) as u32
+    }
+}
+
+/// Number of loop start positions tried per pitch period
+const START_STEPS: usize = 16;
+
+/// Finds a loop inside `vowel_start..vowel_end` which is a whole number of pitch periods long
+/// and where the waveform before the end best matches the waveform before the start.
+/// Falls back to the whole vowel if the region is too short or the pitch is unknown.
+pub fn find_loop(samples: &[f32], vowel_start: u32, vowel_end: u32, period: f32) -> LoopPoints {
+    let whole_vowel = LoopPoints {
+        start: vowel_start,
+        end: vowel_end.max(vowel_start),
+    };
+    let vowel_end = vowel_end.min(samples.len() as u32) as usize;
+    if !period.is_finite() || period < 2.0 {
+        return whole_vowel;
+    }
+
+    // compare two periods before each loop point
+    let window = (period * 2.0) as usize;
+    let period = period as usize;
+    let earliest_start = vowel_start as usize + window;
+    if earliest_start + window + period * 3 >= vowel_end {
+        return whole_vowel;
+    }
+    let matching = |start: usize, end: usize| {
+        correlation(&samples[start - window..start], &samples[end - window..end])
+    };
+
+    // the frq file only has the average pitch, which drifts too far over a long loop,
+    // so the length is found by checking every end over more than a period
+    let length = best(
+        (vowel_end - period * 5 / 2..vowel_end - period)
+            .map(|end| (matching(earliest_start, end), end - earliest_start)),
+    )
+    .unwrap_or(vowel_end - earliest_start);
+
+    // then the loop is slid along by up to a period to where it matches best
+    let start = best(
+        (earliest_start..earliest_start + period)
+            .step_by((period / START_STEPS).max(1))
+            .map(|start| (matching(start, start + length), start)),
+    )
+    .unwrap_or(earliest_start);
+
+    LoopPoints {
+        start: start as u32,
+        end: (start + length) as u32,
+    }
+}
+
+/// Finds a loop of two pitch periods in the second half of `sustain_loop`
+/// where the waveform changes the least from one period to the next
+pub fn find_freeze(samples: &[f32], sustain_loop: LoopPoints, period: f32) -> LoopPoints {
+    if !period.is_finite() || period < 2.0 {
+        return sustain_loop;
+    }
+    let window = (period * 2.0) as usize;
+    let period = period as usize;
+    let earliest_start =
+        (sustain_loop.start as usize + sustain_loop.length() as usize / 2).max(window);
+    let latest_start = (sustain_loop.end as usize).saturating_sub(period * 3);
+    if earliest_start >= latest_start {
+        return sustain_loop;
+    }
+
+    let start = best(
+        (earliest_start..latest_start)
+            .step_by((period / 4).max(1))
+            .map(|start| {
+                let stability = correlation(
+                    &samples[start..start + period],
+                    &samples[start + period..start + period * 2],
+                );
+                (stability, start)
+            }),
+    )
+    .unwrap_or(earliest_start);
+
+    // the exact length is found the same way as for the sustain loop
+    let end = best((start + period * 3 / 2..start + period * 5 / 2).map(|end| {
+        let matching = correlation(&samples[start - window..start], &samples[end - window..end]);
+        (matching, end)
+    }))
+    .unwrap_or(start + period * 2);
+
+    LoopPoints {
+        start: start as u32,
+        end: end as u32,
+    }
+}
+
+/// The candidate with the highest score
+pub fn best<T>(candidates: impl Iterator<Item = (f32, T)>) -> Option<T> {
+    candidates
+        .fold(None, |best: Option<(f32, T)>, candidate| match best {
+            Some(best) if best.0 >= candidate.0 => Some(best),
+            _ => Some(candidate),
+        })
+        .map(|(_, candidate)| candidate)
+}
+
+/// Normalized cross-correlation of two equally long windows, from -1.0 to 1.0
+pub fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let (mut product, mut power_a, mut power_b) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b.iter()) {
+        product += a * b;
+        power_a += a * a;
+        power_b += b * b;
+    }
+    if power_a <= f32::EPSILON || power_b <= f32::EPSILON {
+        return 0.0;
+    }
+    product / (power_a * power_b).sqrt()
+}
+
+/// Gains of the outgoing and incoming signal `ratio` of the way through an equal-power crossfade
+pub fn equal_power(ratio: f32) -> (f32, f32) {
+    let angle = ratio.clamp(0.0, 1.0) * std::f32::consts::FRAC_PI_2;
+    (angle.cos(), angle.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: f32 = 189.3;
+
+    /// A vowel-like tone whose level drifts slowly, so not every loop matches equally well
+    fn vowel(length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|i| {
+                let phase = i as f32 / PERIOD * std::f32::consts::TAU;
+                let level = 1.0 + 0.2 * (i as f32 / 3000.0).sin();
+                (phase.sin() + 0.3 * (phase * 3.0).sin()) * level
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loop_holds_whole_periods() {
+        let samples = vowel(44100);
+        let sustain = find_loop(&samples, 4000, 40000, PERIOD);
+        assert!(sustain.start >= 4000 && sustain.end <= 40000);
+        let periods = sustain.length() as f32 / PERIOD;
+        assert!(
+            (periods - periods.round()).abs() < 0.05,
+            "{periods} periods"
+        );
+        assert!(sustain.length() as f32 > (40000 - 4000) as f32 * 0.8);
+    }
+
+    #[test]
+    fn loop_falls_back_to_the_whole_vowel() {
+        let samples = vowel(44100);
+        let whole = LoopPoints {
+            start: 4000,
+            end: 40000,
+        };
+        assert_eq!(find_loop(&samples, 4000, 40000, 0.0), whole);
+        assert_eq!(find_loop(&samples, 4000, 40000, f32::NAN), whole);
+        // too short for the comparison windows
+        let short = find_loop(&samples, 4000, 4500, PERIOD);
+        assert_eq!(
+            short,
+            LoopPoints {
+                start: 4000,
+                end: 4500
+            }
+        );
+        // an end before the start is an empty loop
+        assert_eq!(
+            find_loop(&samples, 4000, 3000, PERIOD),
+            LoopPoints {
+                start: 4000,
+                end: 4000
+            }
+        );
+    }
+
+    #[test]
+    fn freeze_picks_two_steady_periods() {
+        let sustain = LoopPoints {
+            start: 4000,
+            end: 40000,
+        };
+        // an inharmonic wobble which dies out towards 30000 makes the earlier periods unstable
+        let samples: Vec<f32> = (0..44100)
+            .map(|i| {
+                let wobble = (30000.0 - i as f32).max(0.0) / 8000.0;
+                (i as f32 / PERIOD * std::f32::consts::TAU).sin()
+                    + wobble * (i as f32 * 0.0123).sin()
+            })
+            .collect();
+        let freeze = find_freeze(&samples, sustain, PERIOD);
+        assert!(
+            freeze.start >= 28000 && freeze.end <= sustain.end,
+            "{freeze:?}"
+        );
+        let periods = freeze.length() as f32 / PERIOD;
+        assert!((periods - 2.0).abs() < 0.05, "{periods} periods");
+    }
+
+    /// Plays a time-stretch on from `position` for `samples` output samples at the recorded speed,
+    /// returning the furthest into the vowel it got
+    fn stretch(stretch: &mut TimeStretch, position: &mut f32, samples: usize, hold: bool) -> f32 {
+        let mut furthest = *position;
+        for _ in 0..samples {
+            *position += 1.0;
+            if stretch.advance(1.0, PERIOD, hold) {
+                *position -= PERIOD;
+            }
+            furthest = furthest.max(*position);
+        }
+        furthest
+    }
+
+    #[test]
+    fn time_stretch_slows_down_without_a_loop_point() {
+        let length = 20000.0;
+        let mut time_stretch = TimeStretch::new(length);
+        let mut position = 0.0;
+        // a quarter of the recorded speed at first
+        stretch(&mut time_stretch, &mut position, 8000, true);
+        assert!((position - 2000.0).abs() < PERIOD * 3.0, "{position}");
+        // and ever slower, without reaching the end of the vowel
+        let furthest = stretch(&mut time_stretch, &mut position, 2_000_000, true);
+        assert!(position > length * 0.9, "{position}");
+        assert!(furthest <= length, "{furthest}");
+    }
+
+    #[test]
+    fn released_time_stretch_plays_on() {
+        let mut time_stretch = TimeStretch::new(20000.0);
+        let mut position = 0.0;
+        stretch(&mut time_stretch, &mut position, 8000, true);
+        let held = position;
+        // the period repeating when the note is let go is the last
+        stretch(&mut time_stretch, &mut position, 4000, false);
+        assert!(position - held > 4000.0 - PERIOD * 2.0, "{position}");
+        assert_eq!(time_stretch.repeat_gain(PERIOD), 0.0);
+    }
+
+    #[test]
+    fn repeated_period_fades_in() {
+        let mut time_stretch = TimeStretch::new(20000.0);
+        let mut gains = vec![];
+        let mut jumps = vec![];
+        for i in 0..2000 {
+            if time_stretch.advance(1.0, PERIOD, true) {
+                jumps.push(i);
+            }
+            gains.push(time_stretch.repeat_gain(PERIOD));
+        }
+        assert!(gains.iter().all(|gain| (0.0..=1.0).contains(gain)));
+        // at a quarter of the recorded speed most periods repeat
+        assert!(jumps.len() >= 6, "{jumps:?}");
+        // each repeat fades in over a whole period, then playback jumps back to it
+        for jump in jumps {
+            assert!(gains[jump - 1] > 0.99);
+            let half = gains[jump - PERIOD as usize / 2];
+            assert!(half > 0.4 && half < 0.6, "{half}");
+        }
+        assert_eq!(TimeStretch::new(0.0).repeat_gain(PERIOD), 0.0);
+    }
+
+    #[test]
+    fn freeze_frame_holds_the_harmonics() {
+        let signal = |position: f32| {
+            let phase = position / PERIOD * std::f32::consts::TAU;
+            phase.sin() + 0.5 * (phase * 3.0 + 0.3).cos() + 0.2 * (phase * 7.0).sin()
+        };
+        let samples: Vec<f32> = (0..5000).map(|i| signal(i as f32)).collect();
+        let period = freeze_frame(&samples, 1000, PERIOD);
+        assert_eq!(period.len(), FROZEN_LENGTH);
+        for (i, sample) in period.iter().enumerate() {
+            let expected = signal(1000.0 + i as f32 * PERIOD / FROZEN_LENGTH as f32);
+            assert!((sample - expected).abs() < 0.05, "{i}: {sample} {expected}");
+        }
+    }
+
+    #[test]
+    fn freeze_frame_past_the_end_is_silent() {
+        let samples = vowel(1000);
+        assert!(freeze_frame(&samples, 900, PERIOD)
+            .iter()
+            .all(|sample| *sample == 0.0));
+        assert!(freeze_frame(&samples, 0, 0.0)
+            .iter()
+            .all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn freeze_falls_back_to_the_sustain_loop() {
+        let samples = vowel(44100);
+        let sustain = LoopPoints {
+            start: 4000,
+            end: 5000,
+        };
+        assert_eq!(find_freeze(&samples, sustain, PERIOD), sustain);
+        assert_eq!(find_freeze(&samples, sustain, 1.0), sustain);
+    }
+
+    #[test]
+    fn stretch_region_moves_what_comes_after_it() {
+        let slower = StretchRegion {
+            start: 100,
+            end: 200,
+            factor: 1.5,
+        };
+        assert_eq!(slower.map(50), 50);
+        assert_eq!(slower.map(100), 100);
+        assert_eq!(slower.map(150), 175);
+        assert_eq!(slower.map(200), 250);
+        assert_eq!(slower.map(300), 350);
+
+        let faster = StretchRegion {
+            factor: 0.5,
+            ..slower
+        };
+        assert_eq!(faster.map(50), 50);
+        assert_eq!(faster.map(150), 125);
+        assert_eq!(faster.map(200), 150);
+        assert_eq!(faster.map(300), 250);
+    }
+}
@@ -0,0 +1,244 @@
+use tdpsola::{AlternatingHann, Speed, TdpsolaAnalysis, TdpsolaSynthesis};
+
+use crate::{
+    breath,
+    flags::Flags,
+    sustain::{best, correlation, freeze_frame, StretchRegion},
+    LoadedSample,
+};
+
+/// Peak level that `P` flag compression pulls samples towards
+const PEAK_TARGET: f32 = 0.5;
+
+/// A pitch shifted copy of a loaded sample, ready for playback
+pub struct Rendered {
+    pub samples: Vec<Vec<f32>>,
+    /// Noise with the spectral envelope of the voice, mixed in by breathiness
+    pub breath: Vec<Vec<f32>>,
+    /// One period of the frozen frame for the freeze sustain mode, empty in the other modes
+    pub frozen: Vec<Vec<f32>>,
+    /// The breath noise frozen the same way
+    pub frozen_breath: Vec<Vec<f32>>,
+    pub target_frequency: f32,
+    pub formant_shift: f32,
+}
+
+impl Rendered {
+    /// Gets the sample at `position` with `breath_mix` of breath noise mixed in.
+    /// The voiced part is ducked as the breath comes in so a full mix sounds whispered.
+    pub fn get(&self, channel: usize, position: usize, breath_mix: f32) -> f32 {
+        let voiced = self
+            .samples
+            .get(channel)
+            .and_then(|samples| samples.get(position))
+            .unwrap_or(&0.0);
+        let breath = self
+            .breath
+            .get(channel)
+            .and_then(|samples| samples.get(position))
+            .unwrap_or(&0.0);
+        voiced * (1.0 - 0.5 * breath_mix) + breath * breath_mix
+    }
+
+    /// Gets the frozen period `cycle` of the way through, from 0.0 to 1.0, mixed like `get`
+    pub fn get_frozen(&self, channel: usize, cycle: f32, breath_mix: f32) -> f32 {
+        let read = |periods: &[Vec<f32>]| {
+            let Some(period) = periods.get(channel).filter(|period| !period.is_empty()) else {
+                return 0.0;
+            };
+            let position = cycle.rem_euclid(1.0) * period.len() as f32;
+            let index = position as usize % period.len();
+            let a = period[index];
+            let b = period[(index + 1) % period.len()];
+            a + (b - a) * position.fract()
+        };
+        read(&self.frozen) * (1.0 - 0.5 * breath_mix) + read(&self.frozen_breath) * breath_mix
+    }
+
+    /// Whether the sample has to be rendered again for a new note or formant shift.
+    /// The formant param steps by a tenth of a semitone, so automating it re-renders at most once per step.
+    pub fn is_stale(&self, target_frequency: f32, formant_shift: f32) -> bool {
+        self.target_frequency != target_frequency || self.formant_shift != formant_shift
+    }
+}
+
+/// Pitch shifts a sample to `target_frequency` and applies the flags which affect the timbre.
+/// `formant_shift` is in semitones and is applied by resampling the input before TD-PSOLA.
+/// With a `stretch` the consonant is also played slower or faster than it was recorded,
+/// so the output can be longer or shorter than the sample.
+/// With a `freeze` position the frame of the output starting there is frozen for the freeze sustain mode.
+pub fn render(
+    sample: &LoadedSample,
+    target_frequency: f32,
+    formant_shift: f32,
+    flags: &Flags,
+    stretch: Option<&StretchRegion>,
+    freeze: Option<usize>,
+    sample_rate: f32,
+) -> Rendered {
+    let formant_ratio = 2.0_f32.powf(formant_shift / 12.0);
+    // resampling by the formant ratio also shifts the pitch, so the source wavelength changes with it
+    let source_wavelength = sample_rate / sample.frequency / formant_ratio;
+    let target_wavelength = sample_rate / target_frequency;
+    let shift = |input: &[f32], speed: f32| {
+        if formant_ratio == 1.0 {
+            psola(input, source_wavelength, target_wavelength, speed)
+        } else {
+            psola(
+                &resample_linear(input, formant_ratio),
+                source_wavelength,
+                target_wavelength,
+                speed / formant_ratio,
+            )
+        }
+    };
+
+    let mut samples: Vec<Vec<f32>> = sample
+        .samples
+        .iter()
+        .map(|channel| {
+            let mut shifted = shift(channel, 1.0);
+            // keep positions lined up with the oto timings of the original sample
+            shifted.resize(channel.len(), 0.0);
+            match stretch {
+                Some(region)
+                    if region.factor != 1.0
+                        && region.length() > 0
+                        && region.end as usize <= channel.len() =>
+                {
+                    let recorded = &channel[region.start as usize..region.end as usize];
+                    let stretched = shift(recorded, 1.0 / region.factor);
+                    splice_stretch(&shifted, region, &stretched, target_wavelength)
+                }
+                _ => shifted,
+            }
+        })
+        .collect();
+
+    let compression = flags.peak_compression();
+    if compression > 0.0 {
+        let peak = samples
+            .iter()
+            .flatten()
+            .fold(0.0_f32, |peak, s| peak.max(s.abs()));
+        if peak > 0.0 {
+            let gain = 1.0 + (PEAK_TARGET / peak - 1.0) * compression;
+            samples.iter_mut().flatten().for_each(|s| *s *= gain);
+        }
+    }
+
+    let breath: Vec<Vec<f32>> = samples
+        .iter()
+        .enumerate()
+        .map(|(channel, samples)| {
+            let mut breath = vec![0.0; samples.len()];
+            breath::shaped_noise(samples, channel as u32 + 1, &mut breath);
+            breath
+        })
+        .collect();
+
+    let freeze_all = |channels: &[Vec<f32>]| -> Vec<Vec<f32>> {
+        match freeze {
+            Some(start) => channels
+                .iter()
+                .map(|channel| freeze_frame(channel, start, target_wavelength))
+                .collect(),
+            None => vec![],
+        }
+    };
+    let frozen = freeze_all(&samples);
+    let frozen_breath = freeze_all(&breath);
+
+    Rendered {
+        samples,
+        breath,
+        frozen,
+        frozen_breath,
+        target_frequency,
+        formant_shift,
+    }
+}
+
+/// Replaces `region` of `shifted` with its `stretched` copy, lining up the pitch periods at both joins
+fn splice_stretch(
+    shifted: &[f32],
+    region: &StretchRegion,
+    stretched: &[f32],
+    wavelength: f32,
+) -> Vec<f32> {
+    let period = (wavelength as usize).max(1);
+    let start = (region.start as usize).min(shifted.len());
+    let end = region.end as usize;
+    let mut out = Vec::with_capacity(shifted.len());
+    out.extend(&shifted[..start]);
+
+    // start the stretched region where it best continues the samples before it
+    let skip = aligned_offset(
+        &shifted[start..(start + period).min(shifted.len())],
+        stretched,
+        period,
+    );
+    let mut region_samples: Vec<f32> = stretched.iter().skip(skip).copied().collect();
+    region_samples.resize(region.stretched_length() as usize, 0.0);
+    out.extend(region_samples);
+
+    // and pick the recording back up where it best continues the stretched region
+    let resume = aligned_offset(
+        &out[out.len().saturating_sub(period)..],
+        &shifted[end.saturating_sub(period).min(shifted.len())..],
+        period,
+    );
+    out.extend(&shifted[(end + resume).min(shifted.len())..]);
+    out
+}
+
+/// Offset into `candidates`, less than `period`, where they best match `target`
+fn aligned_offset(target: &[f32], candidates: &[f32], period: usize) -> usize {
+    let scores = (0..period)
+        .filter(|offset| offset + target.len() <= candidates.len())
+        .map(|offset| {
+            let score = correlation(target, &candidates[offset..offset + target.len()]);
+            (score, offset)
+        });
+    best(scores).unwrap_or(0)
+}
+
+/// Pitch shifts one channel with TD-PSOLA, `speed` below 1.0 stretches the output
+pub fn psola(
+    input: &[f32],
+    source_wavelength: f32,
+    target_wavelength: f32,
+    speed: f32,
+) -> Vec<f32> {
+    let mut alternating_hann = AlternatingHann::new(source_wavelength);
+    let mut analysis = TdpsolaAnalysis::new(&alternating_hann);
+
+    let padding_length = source_wavelength as usize + 1;
+    for _ in 0..padding_length {
+        analysis.push_sample(0.0, &mut alternating_hann);
+    }
+    for sample in input.iter() {
+        analysis.push_sample(*sample, &mut alternating_hann);
+    }
+
+    let mut synthesis = TdpsolaSynthesis::new(Speed::from_f32(speed), target_wavelength);
+    synthesis
+        .iter(&analysis)
+        .skip((padding_length as f32 / speed) as usize)
+        .collect()
+}
+
+/// Resamples with linear interpolation, a `ratio` above 1.0 shortens the input
+pub fn resample_linear(input: &[f32], ratio: f32) -> Vec<f32> {
+    let length = (input.len() as f32 / ratio) as usize;
+    (0..length)
+        .map(|i| {
+            let position = i as f32 * ratio;
+            let index = position as usize;
+            let fraction = position - index as f32;
+            let a = input.get(index).copied().unwrap_or(0.0);
+            let b = input.get(index + 1).copied().unwrap_or(a);
+            a + (b - a) * fraction
+        })
+        .collect()
+}
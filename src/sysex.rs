@@ -1,46 +1,130 @@
 use nih_plug::{nih_log, prelude::SysExMessage};
 use serde::{Deserialize, Serialize};
 
-use crate::lyrics::Lyric;
+use crate::{lyrics::Lyric, nsx1, romaji};
+
+/// Longest SysEx message that can be received, including the `F0` and `F7` bytes
+pub const MAX_SYSEX_LENGTH: usize = 128;
+/// Manufacturer ID reserved for non-commercial use, Plutau's own messages use it.
+/// Messages starting with it are never read as the short or long forms,
+/// so lyrics from U+7D00 to U+7DFF have to be sent as UTF-8.
+pub const MANUFACTURER_ID: u8 = 0x7D;
+/// Command byte of a message which sets the lyric to a UTF-8 string
+pub const COMMAND_LYRIC: u8 = 0x01;
+
+/// How the lyric is stored in the message
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Default)]
+pub enum SysExFormat {
+    /// `F0 hi lo F7`, a single UTF-16 code unit
+    Short,
+    /// `F0 hi lo hi lo F7`, two UTF-16 code units
+    #[default]
+    Long,
+    /// `F0 7D 01 <7-bit packed UTF-8> F7`, a string of any length that fits in the buffer
+    Utf8,
+    /// `F0 43 79 09 00 50 10 <phonetic symbols> 00 F7`, Yamaha NSX-1 syllables separated by commas.
+    /// This is the only NSX-1 message that is read. The others, like the ones which pick a lyric slot
+    /// or set the syllable position, are rejected and logged.
+    Nsx1,
+}
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(from = "SavedSysExLyric")]
 pub struct SysExLyric {
-    raw: [u8; 6],
+    #[serde(with = "raw_bytes")]
+    raw: [u8; MAX_SYSEX_LENGTH],
+    length: usize,
+    format: SysExFormat,
+    /// Syllable of an NSX-1 message the next note sings, moved on by `advance`
+    syllable: usize,
+}
+
+/// A saved `SysExLyric`, which may be from before the variable-length protocol.
+/// Those saves have no length or format, only a 6 byte message and whether it was the short form.
+#[derive(Deserialize)]
+struct SavedSysExLyric {
+    #[serde(with = "raw_bytes")]
+    raw: [u8; MAX_SYSEX_LENGTH],
+    length: Option<usize>,
+    #[serde(default)]
+    format: SysExFormat,
+    #[serde(default)]
+    syllable: usize,
+    #[serde(default)]
     short: bool,
 }
 
+impl From<SavedSysExLyric> for SysExLyric {
+    fn from(saved: SavedSysExLyric) -> Self {
+        let (length, format) = match saved.length {
+            Some(length) => (length.min(MAX_SYSEX_LENGTH), saved.format),
+            // the short form was padded to 6 bytes, only the first 4 are the message
+            None if saved.short => (4, SysExFormat::Short),
+            None => (6, SysExFormat::Long),
+        };
+        let mut raw = [0u8; MAX_SYSEX_LENGTH];
+        raw[..length].copy_from_slice(&saved.raw[..length]);
+        Self {
+            raw,
+            length,
+            format,
+            syllable: saved.syllable,
+        }
+    }
+}
+
 impl SysExMessage for SysExLyric {
-    type Buffer = [u8; 6];
+    type Buffer = [u8; MAX_SYSEX_LENGTH];
 
     fn from_buffer(buffer: &[u8]) -> Option<Self> {
-        let processed_sysex: [u8; 6];
-        let four_byte: bool;
         nih_log!("Lyric SysEx: {:x?}", buffer);
-        if buffer.len() == 4 {
-            processed_sysex = [buffer[0], buffer[1], buffer[2], buffer[3], 0x00, buffer[3]];
-            four_byte = true;
+        if buffer.len() > MAX_SYSEX_LENGTH {
+            nih_log!("SysEx too long for a lyric: {} bytes", buffer.len());
+            return None;
+        }
+
+        let plutau = buffer.len() >= 3 && buffer[0] == 0xf0 && buffer[1] == MANUFACTURER_ID;
+        if plutau && buffer[2] != COMMAND_LYRIC {
+            nih_log!("Unknown SysEx command: {:x}", buffer[2]);
+            return None;
+        }
+
+        let nsx1_lyric = buffer.starts_with(&nsx1::LYRIC_HEADER);
+        if buffer.starts_with(&nsx1::HEADER) && !nsx1_lyric {
+            nih_log!(
+                "Unsupported NSX-1 SysEx, only phonetic symbol lyrics are read: {:x?}",
+                buffer
+            );
+            return None;
+        }
+
+        let format = if plutau {
+            SysExFormat::Utf8
+        } else if nsx1_lyric {
+            SysExFormat::Nsx1
+        } else if buffer.len() == 4 {
+            SysExFormat::Short
         } else {
-            processed_sysex = match buffer.try_into() {
-                Ok(arr) => arr,
-                Err(_) => {
-                    nih_log!("Buffer conversion failed, using [0; 6]: {:x?}", buffer);
-                    [0u8; 6]
-                }
-            };
-            four_byte = false;
-        }
-        if !Self::is_valid(&processed_sysex) {
-            nih_log!("Invalid SysEx lyric: {:x?}", processed_sysex);
+            SysExFormat::Long
+        };
+
+        let mut raw = [0u8; MAX_SYSEX_LENGTH];
+        raw[..buffer.len()].copy_from_slice(buffer);
+        let lyric = Self {
+            raw,
+            length: buffer.len(),
+            format,
+            syllable: 0,
+        };
+        if !lyric.is_lyric() {
+            nih_log!("Invalid SysEx lyric: {:x?}", buffer);
             return None;
         }
-        Option::Some(Self {
-            raw: processed_sysex,
-            short: four_byte,
-        })
+        Option::Some(lyric)
     }
 
     fn to_buffer(self) -> (Self::Buffer, usize) {
-        (self.raw, 6)
+        (self.raw, self.length)
     }
 }
 
@@ -51,49 +135,332 @@ impl Default for SysExLyric {
 }
 
 impl SysExLyric {
+    /// Builds a variable-length lyric message, `None` if the lyric doesn't fit in the buffer
+    pub fn from_utf8(lyric: &str) -> Option<Self> {
+        let mut message = vec![0xf0, MANUFACTURER_ID, COMMAND_LYRIC];
+        message.extend(pack_7bit(lyric.as_bytes()));
+        message.push(0xf7);
+        Self::from_buffer(&message)
+    }
+
     pub fn is_lyric(&self) -> bool {
-        nih_log!("Lyric: {:x?}, {}", self.raw, self.raw.len());
-        Self::is_valid(&self.raw)
+        nih_log!("Lyric: {:x?}, {}", self.bytes(), self.length);
+        Self::is_valid(self.bytes(), self.format)
     }
-    pub fn is_valid(raw: &[u8]) -> bool {
-        if raw.len() != 6 {
+
+    pub fn is_valid(raw: &[u8], format: SysExFormat) -> bool {
+        let expected_length = match format {
+            SysExFormat::Short => raw.len() == 4,
+            SysExFormat::Long => raw.len() == 6,
+            SysExFormat::Utf8 => raw.len() >= 4,
+            // at least one symbol before the closing `00 F7`
+            SysExFormat::Nsx1 => {
+                raw.len() > nsx1::LYRIC_HEADER.len() + 2
+                    && raw[nsx1::LYRIC_HEADER.len()..raw.len() - 1]
+                        .iter()
+                        .any(|byte| !byte.is_ascii_whitespace() && *byte != 0x00 && *byte != b',')
+            }
+        };
+        if !expected_length {
             return false;
         }
-        if raw[0] == 0xff && raw.last().unwrap_or(&0u8).clone() == 0x05 {
+        let last = raw.last().copied().unwrap_or(0u8);
+        if raw[0] == 0xff && last == 0x05 {
             nih_log!("Lyric using lyric event: {:x?}", raw);
             return true;
         }
-        if raw[0] == 0xff && raw.last().unwrap_or(&0u8).clone() == 0x01 {
+        if raw[0] == 0xff && last == 0x01 {
             nih_log!("Lyric using text event: {:x?}", raw);
             return true;
         }
-        if raw[0] == 0xf0 && raw.last().unwrap_or(&0u8).clone() == 0xf7 {
+        if raw[0] == 0xf0 && last == 0xf7 {
             nih_log!("Lyric using SysEx event: {:x?}", raw);
             return true;
         }
         false
     }
+
+    fn bytes(&self) -> &[u8] {
+        &self.raw[..self.length]
+    }
+
+    /// Kana for the current syllable of an NSX-1 message, going back to the first after the last.
+    /// Symbols which aren't in the table are returned as they are, so they can still match an alias.
+    fn nsx1_syllable(&self) -> String {
+        let symbols = String::from_utf8_lossy(&self.raw[nsx1::LYRIC_HEADER.len()..self.length - 1]);
+        let syllables: Vec<&str> = symbols
+            .trim_end_matches('\0')
+            .split(',')
+            .map(|syllable| syllable.trim())
+            .filter(|syllable| !syllable.is_empty())
+            .collect();
+        let syllable = syllables[self.syllable % syllables.len()];
+        nih_log!("Lyric NSX-1: {}", syllable);
+        nsx1::symbols_to_kana(syllable)
+            .map(String::from)
+            .unwrap_or_else(|| syllable.to_string())
+    }
 }
 
 impl Lyric for SysExLyric {
     fn get_jpn_utf8(&mut self) -> String {
-        let lyric: [u8; 4];
-        if self.short {
-            lyric = [self.raw[1], self.raw[2], 0, 0];
-            nih_log!("Lyric short: {:x?}", lyric);
-        } else {
-            lyric = [self.raw[1], self.raw[2], self.raw[3], self.raw[4]];
-            nih_log!("Lyric long: {:x?}", lyric);
-        }
-        let mut lyric_16: Vec<u16> = vec![];
-        lyric_16.push(((lyric[0] as u16) << 8) | lyric[1] as u16);
-        if !self.short {
-            lyric_16.push(((lyric[2] as u16) << 8) | lyric[3] as u16);
-        }
+        let lyric_16: Vec<u16> = match self.format {
+            SysExFormat::Utf8 => {
+                // skip F0, the manufacturer ID and the command, and drop F7
+                let lyric = unpack_7bit(&self.raw[3..self.length - 1]);
+                nih_log!("Lyric UTF-8: {:x?}", lyric);
+                return String::from_utf8_lossy(&lyric).trim().to_string();
+            }
+            SysExFormat::Nsx1 => return self.nsx1_syllable(),
+            SysExFormat::Short => {
+                nih_log!("Lyric short: {:x?}", &self.raw[1..3]);
+                vec![((self.raw[1] as u16) << 8) | self.raw[2] as u16]
+            }
+            SysExFormat::Long => {
+                nih_log!("Lyric long: {:x?}", &self.raw[1..5]);
+                vec![
+                    ((self.raw[1] as u16) << 8) | self.raw[2] as u16,
+                    ((self.raw[3] as u16) << 8) | self.raw[4] as u16,
+                ]
+            }
+        };
         String::from_utf16_lossy(&lyric_16).trim().to_string()
     }
     fn get_latin(&mut self) -> String {
-        //TODO: implement conversion from jpn_utf8 to latin
-        "".to_string()
+        romaji::to_romaji(&self.get_jpn_utf8())
+    }
+    fn advance(&mut self) {
+        if self.format == SysExFormat::Nsx1 {
+            self.syllable += 1;
+        }
+    }
+}
+
+/// Packs 8-bit data so every byte fits in a SysEx message.
+/// Each group of up to 7 bytes is sent as a byte holding their top bits (bit 0 for the first byte),
+/// followed by the bytes with their top bit cleared.
+pub fn pack_7bit(data: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(data.len() + data.len().div_ceil(7));
+    for group in data.chunks(7) {
+        let top_bits = group
+            .iter()
+            .enumerate()
+            .fold(0u8, |bits, (i, byte)| bits | ((byte >> 7) << i));
+        packed.push(top_bits);
+        packed.extend(group.iter().map(|byte| byte & 0x7f));
+    }
+    packed
+}
+
+/// Reverses `pack_7bit`
+pub fn unpack_7bit(packed: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(packed.len());
+    for group in packed.chunks(8) {
+        let top_bits = group[0];
+        data.extend(
+            group[1..]
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| (byte & 0x7f) | (((top_bits >> i) & 1) << 7)),
+        );
+    }
+    data
+}
+
+/// Serde only handles arrays up to 32 long, so the raw message is saved as a list of bytes
+mod raw_bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::MAX_SYSEX_LENGTH;
+
+    pub fn serialize<S: Serializer>(
+        raw: &[u8; MAX_SYSEX_LENGTH],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        raw.as_slice().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[u8; MAX_SYSEX_LENGTH], D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let mut raw = [0u8; MAX_SYSEX_LENGTH];
+        let length = bytes.len().min(MAX_SYSEX_LENGTH);
+        raw[..length].copy_from_slice(&bytes[..length]);
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lyric(buffer: &[u8]) -> String {
+        SysExLyric::from_buffer(buffer).unwrap().get_jpn_utf8()
+    }
+
+    #[test]
+    fn short_and_long_forms() {
+        assert_eq!(lyric(&[0xf0, 0x30, 0x42, 0xf7]), "あ");
+        assert_eq!(lyric(&[0xf0, 0x30, 0x4d, 0x30, 0x83, 0xf7]), "きゃ");
+        assert!(SysExLyric::from_buffer(&[0xf0, 0x30, 0x42, 0x00, 0x00]).is_none());
+    }
+
+    #[test]
+    fn utf8_round_trip() {
+        for text in ["きゃ", "a か", "あいうえおかきくけこさしすせそ", ""] {
+            let message = SysExLyric::from_utf8(text).unwrap();
+            let (buffer, length) = message.to_buffer();
+            assert!(buffer[1..length - 1].iter().all(|byte| byte & 0x80 == 0));
+            assert_eq!(lyric(&buffer[..length]), text);
+        }
+    }
+
+    #[test]
+    fn legacy_forms_round_trip() {
+        for message in [
+            vec![0xf0, 0x30, 0x42, 0xf7],
+            vec![0xf0, 0x30, 0x4d, 0x30, 0x83, 0xf7],
+        ] {
+            let (buffer, length) = SysExLyric::from_buffer(&message).unwrap().to_buffer();
+            assert_eq!(&buffer[..length], message.as_slice());
+        }
+    }
+
+    #[test]
+    fn loads_legacy_saves() {
+        let mut short: SysExLyric =
+            serde_yaml::from_str(r#"{"raw":[240,48,66,247,0,247],"short":true}"#).unwrap();
+        assert_eq!(short, SysExLyric::default());
+        assert_eq!(short.get_jpn_utf8(), "あ");
+
+        let mut long: SysExLyric =
+            serde_yaml::from_str(r#"{"raw":[240,48,77,48,131,247],"short":false}"#).unwrap();
+        assert_eq!(long.to_buffer().1, 6);
+        assert_eq!(long.get_jpn_utf8(), "きゃ");
+    }
+
+    #[test]
+    fn saves_round_trip() {
+        let mut message = nsx1::LYRIC_HEADER.to_vec();
+        message.extend(b"k' a,4 a");
+        message.extend([0x00, 0xf7]);
+        let mut nsx1 = SysExLyric::from_buffer(&message).unwrap();
+        nsx1.advance();
+        for lyric in [
+            SysExLyric::default(),
+            SysExLyric::from_utf8("きゃ").unwrap(),
+            nsx1,
+        ] {
+            let saved = serde_yaml::to_string(&lyric).unwrap();
+            assert_eq!(serde_yaml::from_str::<SysExLyric>(&saved).unwrap(), lyric);
+        }
+    }
+
+    #[test]
+    fn rejects_long_messages() {
+        let mut message = vec![0xf0, MANUFACTURER_ID, COMMAND_LYRIC];
+        message.resize(MAX_SYSEX_LENGTH - 1, 0x00);
+        message.push(0xf7);
+        assert!(SysExLyric::from_buffer(&message).is_some());
+        // one byte more than fits
+        message.insert(3, 0x00);
+        assert!(SysExLyric::from_buffer(&message).is_none());
+        assert!(SysExLyric::from_utf8(&"あ".repeat(MAX_SYSEX_LENGTH)).is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        let (buffer, length) = SysExLyric::from_utf8("か").unwrap().to_buffer();
+        let mut message = buffer[..length].to_vec();
+        message[2] = 0x02;
+        assert!(SysExLyric::from_buffer(&message).is_none());
+        // six bytes like the long form
+        assert!(
+            SysExLyric::from_buffer(&[0xf0, MANUFACTURER_ID, 0x02, 0x30, 0x42, 0xf7]).is_none()
+        );
+        assert!(SysExLyric::from_buffer(&[0xf0, MANUFACTURER_ID, 0x02, 0xf7]).is_none());
+    }
+
+    #[test]
+    fn nsx1_syllables() {
+        let mut message = nsx1::LYRIC_HEADER.to_vec();
+        message.extend(b"k' a,4 a,N\\");
+        message.extend([0x00, 0xf7]);
+        let mut lyric = SysExLyric::from_buffer(&message).unwrap();
+        for syllable in ["きゃ", "ら", "ん", "きゃ"] {
+            assert_eq!(lyric.get_jpn_utf8(), syllable);
+            lyric.advance();
+        }
+    }
+
+    #[test]
+    fn nsx1_notes_sing_one_syllable_each() {
+        let mut message = nsx1::LYRIC_HEADER.to_vec();
+        message.extend(b"s a,k' i,s M,4 a,m e");
+        message.extend([0x00, 0xf7]);
+        let mut lyric = SysExLyric::from_buffer(&message).unwrap();
+        let mut sung = vec![];
+        for _ in 0..7 {
+            // reading the lyric, like logging it does, doesn't use up the syllable
+            assert_eq!(lyric.get_jpn_utf8(), lyric.get_jpn_utf8());
+            sung.push(lyric.get_jpn_utf8());
+            lyric.advance();
+        }
+        assert_eq!(sung, ["さ", "き", "す", "ら", "め", "さ", "き"]);
+    }
+
+    #[test]
+    fn nsx1_symbols_map_to_the_bank_kana() {
+        for (symbols, kana) in [
+            ("k' a", "きゃ"),
+            ("  tS   i ", "ち"),
+            ("w o", "を"),
+            ("N", "ん"),
+            ("m'", "ん"),
+            ("p\\' i", "ふぃ"),
+        ] {
+            assert_eq!(nsx1::symbols_to_kana(symbols), Some(kana), "{symbols}");
+        }
+        assert_eq!(nsx1::symbols_to_kana("x y"), None);
+    }
+
+    #[test]
+    fn nsx1_empty_syllables_are_skipped() {
+        let mut message = nsx1::LYRIC_HEADER.to_vec();
+        message.extend(b"k a,, 4 a,");
+        message.extend([0x00, 0xf7]);
+        let mut lyric = SysExLyric::from_buffer(&message).unwrap();
+        for syllable in ["か", "ら", "か"] {
+            assert_eq!(lyric.get_jpn_utf8(), syllable);
+            lyric.advance();
+        }
+
+        // a lyric message without any symbols isn't a lyric
+        for symbols in [&b""[..], b" ", b",,"] {
+            let mut message = nsx1::LYRIC_HEADER.to_vec();
+            message.extend(symbols);
+            message.extend([0x00, 0xf7]);
+            assert!(SysExLyric::from_buffer(&message).is_none());
+        }
+    }
+
+    #[test]
+    fn other_nsx1_messages_are_rejected() {
+        for message in [
+            // lyric slot select
+            vec![0xf0, 0x43, 0x79, 0x09, 0x00, 0x50, 0x11, 0x00, 0xf7],
+            // six bytes like the long form
+            vec![0xf0, 0x43, 0x79, 0x09, 0x01, 0xf7],
+            vec![0xf0, 0x43, 0x79, 0x09, 0x11, 0x0a, 0x00, 0x05, 0xf7],
+        ] {
+            assert!(SysExLyric::from_buffer(&message).is_none(), "{message:x?}");
+        }
+    }
+
+    #[test]
+    fn packing_keeps_top_bits() {
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(unpack_7bit(&pack_7bit(&data)), data);
     }
 }
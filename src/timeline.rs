@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{expression::NoteExpression, flags::Flags};
+
+/// How far after a lyric a note can start and still be sung with it, in beats.
+/// Covers rounding between the host's position and the ticks of imported files.
+const BEAT_TOLERANCE: f64 = 1.0 / 64.0;
+
+/// A lyric sung by the note starting at `beat`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimedLyric {
+    pub beat: f64,
+    pub lyric: String,
+    #[serde(default)]
+    pub flags: Flags,
+    #[serde(default)]
+    pub expression: NoteExpression,
+}
+
+impl TimedLyric {
+    pub fn new(beat: f64, lyric: String, flags: Flags) -> Self {
+        Self {
+            beat,
+            lyric,
+            flags,
+            expression: NoteExpression::default(),
+        }
+    }
+}
+
+/// Lyrics keyed by song position in beats, so lookups follow the host transport
+/// through seeks and loops instead of counting notes
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LyricTimeline {
+    /// Sorted by beat
+    entries: Vec<TimedLyric>,
+}
+
+impl LyricTimeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a lyric, replacing one already at the same beat
+    pub fn insert(&mut self, entry: TimedLyric) {
+        match self.find(entry.beat) {
+            Some(index) => self.entries[index] = entry,
+            None => {
+                let index = self
+                    .entries
+                    .partition_point(|other| other.beat < entry.beat);
+                self.entries.insert(index, entry);
+            }
+        }
+    }
+
+    /// The lyric of the note starting at `beat`, which is the last lyric at or before it.
+    /// Notes before the first lyric get the first lyric.
+    pub fn lyric_at(&self, beat: f64) -> Option<&TimedLyric> {
+        let index = self
+            .entries
+            .partition_point(|entry| entry.beat <= beat + BEAT_TOLERANCE);
+        self.entries.get(index.saturating_sub(1))
+    }
+
+    /// The lyric at `beat` when there is a song position, otherwise the lyric after the last one
+    /// handed out, counted by `index`
+    pub fn next(&self, beat: Option<f64>, index: &mut usize) -> Option<&TimedLyric> {
+        match beat {
+            Some(beat) => self.lyric_at(beat),
+            None => {
+                let entry = self.entries.get(*index % self.entries.len().max(1));
+                *index += 1;
+                entry
+            }
+        }
+    }
+
+    /// Index of the lyric at `beat`, `None` if there isn't one there
+    pub fn find(&self, beat: f64) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| (entry.beat - beat).abs() <= BEAT_TOLERANCE)
+    }
+
+    /// The lyrics separated by spaces, for showing in the editor
+    pub fn text(&self) -> String {
+        self.entries
+            .iter()
+            .map(|entry| entry.lyric.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    pub fn entries(&self) -> &[TimedLyric] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeline(lyrics: &[(f64, &str)]) -> LyricTimeline {
+        let mut timeline = LyricTimeline::new();
+        for (beat, lyric) in lyrics {
+            timeline.insert(TimedLyric::new(*beat, lyric.to_string(), Flags::default()));
+        }
+        timeline
+    }
+
+    fn lyric(entry: Option<&TimedLyric>) -> Option<&str> {
+        entry.map(|entry| entry.lyric.as_str())
+    }
+
+    #[test]
+    fn insert_keeps_lyrics_sorted() {
+        let mut timeline = timeline(&[(2.0, "う"), (0.0, "あ"), (1.0, "い")]);
+        assert_eq!(timeline.text(), "あ い う");
+        // a lyric within the tolerance replaces the one already there
+        timeline.insert(TimedLyric::new(1.01, String::from("え"), Flags::default()));
+        assert_eq!(timeline.text(), "あ え う");
+        assert_eq!(timeline.find(1.0), Some(1));
+        assert_eq!(timeline.find(1.5), None);
+    }
+
+    #[test]
+    fn lyric_at_follows_the_song_position() {
+        let timeline = timeline(&[(1.0, "あ"), (2.0, "い")]);
+        assert_eq!(lyric(timeline.lyric_at(0.0)), Some("あ"));
+        assert_eq!(lyric(timeline.lyric_at(1.5)), Some("あ"));
+        // a note a little before its lyric still gets it
+        assert_eq!(lyric(timeline.lyric_at(1.99)), Some("い"));
+        assert_eq!(lyric(timeline.lyric_at(10.0)), Some("い"));
+        assert_eq!(LyricTimeline::new().lyric_at(0.0), None);
+    }
+
+    #[test]
+    fn next_counts_notes_without_a_song_position() {
+        let timeline = timeline(&[(0.0, "あ"), (1.0, "い")]);
+        let mut index = 0;
+        assert_eq!(lyric(timeline.next(None, &mut index)), Some("あ"));
+        assert_eq!(lyric(timeline.next(None, &mut index)), Some("い"));
+        assert_eq!(lyric(timeline.next(None, &mut index)), Some("あ"));
+        // with a position the count is left alone
+        assert_eq!(lyric(timeline.next(Some(1.0), &mut index)), Some("い"));
+        assert_eq!(index, 3);
+        assert_eq!(LyricTimeline::new().next(None, &mut index), None);
+    }
+
+    #[test]
+    fn clear_empties_it() {
+        let mut timeline = timeline(&[(0.0, "あ")]);
+        assert!(!timeline.is_empty());
+        timeline.clear();
+        assert!(timeline.is_empty());
+        assert_eq!(timeline.text(), "");
+    }
+}
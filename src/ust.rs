@@ -0,0 +1,346 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use nih_plug::nih_log;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    expression::{NoteExpression, PitchBend, UstEnvelope},
+    flags::Flags,
+    lyrics::Lyric,
+    midi_file::decode,
+    romaji,
+    timeline::{LyricTimeline, TimedLyric},
+};
+
+/// UST positions are always in ticks of a 480th of a beat
+pub const TICKS_PER_BEAT: u32 = 480;
+
+/// A note section (`[#0000]`) of a UST file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UstNote {
+    /// Start of the note in ticks, from the lengths of the notes before it
+    pub position: u32,
+    pub length: u32,
+    pub note_num: u8,
+    pub lyric: String,
+    pub flags: String,
+    pub expression: NoteExpression,
+}
+
+impl UstNote {
+    /// Rests are written as notes with an `R` lyric
+    pub fn is_rest(&self) -> bool {
+        let lyric = self.lyric.trim();
+        lyric.is_empty() || lyric.eq_ignore_ascii_case("r")
+    }
+}
+
+/// An UTAU sequence
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ust {
+    pub tempo: f64,
+    pub project_name: String,
+    pub voice_dir: String,
+    /// Flags for every note, from the `[#SETTING]` section
+    pub flags: String,
+    pub notes: Vec<UstNote>,
+}
+
+impl Ust {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|err| err.to_string())?;
+        Ok(Self::parse(&decode(&bytes)))
+    }
+
+    /// Parses the text of a UST file, ignoring anything it doesn't know
+    pub fn parse(text: &str) -> Self {
+        let mut ust = Self {
+            tempo: 120.0,
+            ..Default::default()
+        };
+        let mut section = String::new();
+        let mut values: Vec<(String, String)> = vec![];
+        let mut position = 0;
+        // the last line closes the final section
+        for line in text
+            .trim_start_matches('\u{feff}')
+            .lines()
+            .chain(["[#END]"])
+        {
+            let line = line.trim();
+            if line.starts_with("[#") && line.ends_with(']') {
+                match section.as_str() {
+                    "SETTING" => ust.read_settings(&values),
+                    number if number.chars().all(|c| c.is_ascii_digit()) && !number.is_empty() => {
+                        let note = read_note(&values, position);
+                        position += note.length;
+                        ust.notes.push(note);
+                    }
+                    _ => {}
+                }
+                section = line[2..line.len() - 1].to_string();
+                values.clear();
+            } else if let Some((key, value)) = line.split_once('=') {
+                values.push((key.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        ust
+    }
+
+    /// Saves the sequence as Shift-JIS, which every version of UTAU can open
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let text = self.to_string().replace('\n', "\r\n");
+        let (bytes, _, _) = encoding_rs::SHIFT_JIS.encode(&text);
+        std::fs::write(path, bytes).map_err(|err| err.to_string())
+    }
+
+    fn read_settings(&mut self, values: &[(String, String)]) {
+        for (key, value) in values {
+            match key.as_str() {
+                "Tempo" => self.tempo = value.parse().unwrap_or(self.tempo),
+                "ProjectName" => self.project_name = value.clone(),
+                "VoiceDir" => self.voice_dir = value.clone(),
+                "Flags" => self.flags = value.clone(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Lyrics of the notes with their flags and expression, keyed by the notes' positions
+    pub fn timeline(&self) -> LyricTimeline {
+        let flags = Flags::parse(&self.flags);
+        let mut timeline = LyricTimeline::new();
+        for note in self.notes.iter().filter(|note| !note.is_rest()) {
+            timeline.insert(TimedLyric {
+                beat: note.position as f64 / TICKS_PER_BEAT as f64,
+                lyric: note.lyric.trim().to_string(),
+                flags: flags.merge(&Flags::parse(&note.flags)),
+                expression: NoteExpression {
+                    // notes without an envelope get UTAU's default one
+                    envelope: Some(note.expression.envelope.unwrap_or_default()),
+                    ..note.expression.clone()
+                },
+            });
+        }
+        timeline
+    }
+}
+
+impl fmt::Display for Ust {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[#VERSION]")?;
+        writeln!(f, "UST Version1.2")?;
+        writeln!(f, "[#SETTING]")?;
+        writeln!(f, "Tempo={:.2}", self.tempo)?;
+        writeln!(f, "Tracks=1")?;
+        writeln!(f, "ProjectName={}", self.project_name)?;
+        writeln!(f, "VoiceDir={}", self.voice_dir)?;
+        writeln!(f, "Flags={}", self.flags)?;
+        writeln!(f, "Mode2=True")?;
+        for (i, note) in self.notes.iter().enumerate() {
+            writeln!(f, "[#{:04}]", i)?;
+            writeln!(f, "Length={}", note.length)?;
+            writeln!(f, "Lyric={}", note.lyric)?;
+            writeln!(f, "NoteNum={}", note.note_num)?;
+            writeln!(f, "Flags={}", note.flags)?;
+            let expression = &note.expression;
+            if let Some(intensity) = expression.intensity {
+                writeln!(f, "Intensity={}", intensity.round())?;
+            }
+            if let Some(velocity) = expression.velocity {
+                writeln!(f, "Velocity={}", velocity.round())?;
+            }
+            if let Some(envelope) = expression.envelope {
+                writeln!(f, "Envelope={}", envelope)?;
+            }
+            if let Some(bend) = &expression.pitch_bend {
+                let (pbs, pbw, pby, pbm) = bend.to_ust();
+                writeln!(f, "PBS={}", pbs)?;
+                writeln!(f, "PBW={}", pbw)?;
+                writeln!(f, "PBY={}", pby)?;
+                writeln!(f, "PBM={}", pbm)?;
+            }
+        }
+        writeln!(f, "[#TRACKEND]")
+    }
+}
+
+fn read_note(values: &[(String, String)], position: u32) -> UstNote {
+    let get = |key: &str| {
+        values
+            .iter()
+            .find(|(other, _)| other == key)
+            .map(|(_, value)| value.as_str())
+    };
+    let number = |key: &str| get(key).and_then(|value| value.parse::<f32>().ok());
+    UstNote {
+        position,
+        length: number("Length").unwrap_or(0.0).max(0.0) as u32,
+        note_num: number("NoteNum").unwrap_or(60.0).clamp(0.0, 127.0) as u8,
+        lyric: get("Lyric").unwrap_or_default().to_string(),
+        flags: get("Flags").unwrap_or_default().to_string(),
+        expression: NoteExpression {
+            velocity: number("Velocity"),
+            intensity: number("Intensity"),
+            envelope: get("Envelope").and_then(UstEnvelope::parse),
+            pitch_bend: get("PBS").and_then(|pbs| {
+                PitchBend::from_ust(
+                    pbs,
+                    get("PBW").unwrap_or_default(),
+                    get("PBY").unwrap_or_default(),
+                    get("PBM").unwrap_or_default(),
+                )
+            }),
+            pitch_deviation: None,
+            length: None,
+        },
+    }
+}
+
+/// Lyrics and expression imported from the notes of a UST file
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UstLyric {
+    pub path: PathBuf,
+    pub timeline: LyricTimeline,
+    /// Song position of the note being started, `None` when the transport isn't playing
+    #[serde(skip)]
+    position: Option<f64>,
+    /// Next note when there is no song position to look it up by
+    #[serde(skip)]
+    pub index: usize,
+    #[serde(skip)]
+    current: Option<TimedLyric>,
+}
+
+impl UstLyric {
+    pub fn new(path: PathBuf) -> Self {
+        let timeline = match Ust::read(&path) {
+            Ok(ust) => ust.timeline(),
+            Err(err) => {
+                nih_log!("Failed to read UST {}: {}", path.display(), err);
+                LyricTimeline::new()
+            }
+        };
+        Self {
+            path,
+            timeline,
+            ..Default::default()
+        }
+    }
+}
+
+impl Lyric for UstLyric {
+    fn get_jpn_utf8(&mut self) -> String {
+        self.current = self.timeline.next(self.position, &mut self.index).cloned();
+        self.current
+            .as_ref()
+            .map(|entry| entry.lyric.clone())
+            .unwrap_or_default()
+    }
+
+    fn get_latin(&mut self) -> String {
+        romaji::to_romaji(&self.get_jpn_utf8())
+    }
+
+    fn get_flags(&mut self) -> Flags {
+        self.current
+            .as_ref()
+            .map(|entry| entry.flags)
+            .unwrap_or_default()
+    }
+
+    fn get_expression(&mut self) -> NoteExpression {
+        self.current
+            .as_ref()
+            .map(|entry| entry.expression.clone())
+            .unwrap_or_default()
+    }
+
+    fn set_position(&mut self, beat: Option<f64>) {
+        self.position = beat;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UST: &str = "\u{feff}[#VERSION]\r
+UST Version1.2\r
+[#SETTING]\r
+Tempo=150.00\r
+ProjectName=test\r
+Flags=g-5\r
+[#0000]\r
+Length=480\r
+Lyric=あ\r
+NoteNum=60\r
+Flags=B60\r
+Intensity=80\r
+Envelope=0,5,35,0,100,100,0\r
+[#0001]\r
+Length=240\r
+Lyric=R\r
+NoteNum=60\r
+[#0002]\r
+Length=480\r
+Lyric=か\r
+NoteNum=62\r
+Velocity=150\r
+PBS=-40;20\r
+PBW=80\r
+[#TRACKEND]\r
+";
+
+    #[test]
+    fn parses_settings_and_notes() {
+        let ust = Ust::parse(UST);
+        assert_eq!(ust.tempo, 150.0);
+        assert_eq!(ust.project_name, "test");
+        assert_eq!(ust.flags, "g-5");
+        let positions: Vec<u32> = ust.notes.iter().map(|note| note.position).collect();
+        assert_eq!(positions, [0, 480, 720]);
+        assert!(ust.notes[1].is_rest());
+
+        let first = &ust.notes[0].expression;
+        assert_eq!(first.intensity, Some(80.0));
+        assert_eq!(first.envelope, Some(UstEnvelope::default()));
+        let last = &ust.notes[2];
+        assert_eq!(last.note_num, 62);
+        assert_eq!(last.expression.velocity, Some(150.0));
+        assert!(last.expression.pitch_bend.is_some());
+    }
+
+    #[test]
+    fn timeline_skips_rests_and_merges_flags() {
+        let timeline = Ust::parse(UST).timeline();
+        let entries = timeline.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].beat, entries[0].lyric.as_str()), (0.0, "あ"));
+        assert_eq!((entries[1].beat, entries[1].lyric.as_str()), (1.5, "か"));
+        assert_eq!(entries[0].flags, Flags::parse("g-5B60"));
+        assert_eq!(entries[1].flags, Flags::parse("g-5"));
+        // notes without an envelope get the default one
+        assert_eq!(entries[1].expression.envelope, Some(UstEnvelope::default()));
+    }
+
+    #[test]
+    fn written_ust_reads_back() {
+        let ust = Ust::parse(UST);
+        assert_eq!(Ust::parse(&ust.to_string()), ust);
+    }
+
+    #[test]
+    fn ignores_unknown_sections_and_values() {
+        let ust = Ust::parse(
+            "[#SETTING]\nTempo=abc\nMode2=True\n[#PREV]\nLength=10\n[#0000]\nLyric=さ\n",
+        );
+        assert_eq!(ust.tempo, 120.0);
+        assert_eq!(ust.notes.len(), 1);
+        assert_eq!(ust.notes[0].length, 0);
+        assert_eq!(ust.notes[0].note_num, 60);
+    }
+}
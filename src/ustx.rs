@@ -0,0 +1,447 @@
+use std::path::{Path, PathBuf};
+
+use nih_plug::nih_log;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    expression::{BendPoint, BendShape, NoteExpression, PitchBend},
+    flags::Flags,
+    lyrics::Lyric,
+    romaji,
+    timeline::{LyricTimeline, TimedLyric},
+};
+
+/// The parts of an OpenUtau project that Plutau reads and writes, everything else in the file is ignored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxProject {
+    pub name: String,
+    pub ustx_version: String,
+    /// Ticks per beat
+    pub resolution: u32,
+    /// Tempo of projects saved before tempo changes were supported
+    pub bpm: f64,
+    pub beat_per_bar: u32,
+    pub beat_unit: u32,
+    pub tempos: Vec<UstxTempo>,
+    pub tracks: Vec<UstxTrack>,
+    pub voice_parts: Vec<UstxPart>,
+}
+
+impl Default for UstxProject {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            ustx_version: String::from("0.6"),
+            resolution: 480,
+            bpm: 120.0,
+            beat_per_bar: 4,
+            beat_unit: 4,
+            tempos: vec![],
+            tracks: vec![],
+            voice_parts: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxTempo {
+    pub position: i64,
+    pub bpm: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxTrack {
+    /// Name of the singer's folder
+    pub singer: String,
+    pub phonemizer: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxPart {
+    pub name: String,
+    pub track_no: usize,
+    /// Start of the part in ticks, note and curve positions are relative to it
+    pub position: i64,
+    pub duration: i64,
+    pub notes: Vec<UstxNote>,
+    pub curves: Vec<UstxCurve>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxNote {
+    pub position: i64,
+    pub duration: i64,
+    pub tone: i32,
+    pub lyric: String,
+    pub pitch: UstxPitch,
+    pub vibrato: UstxVibrato,
+    pub phoneme_overrides: Vec<UstxPhonemeOverride>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxPitch {
+    pub data: Vec<UstxPitchPoint>,
+    pub snap_first: bool,
+}
+
+/// A note's vibrato, written out in full since OpenUtau expects every note to have one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxVibrato {
+    /// Percentage of the note the vibrato covers, 0 turns it off
+    pub length: f32,
+    pub period: f32,
+    pub depth: f32,
+    #[serde(rename = "in")]
+    pub fade_in: f32,
+    #[serde(rename = "out")]
+    pub fade_out: f32,
+    pub shift: f32,
+    pub drift: f32,
+}
+
+impl Default for UstxVibrato {
+    /// OpenUtau's defaults, switched off
+    fn default() -> Self {
+        Self {
+            length: 0.0,
+            period: 175.0,
+            depth: 25.0,
+            fade_in: 10.0,
+            fade_out: 10.0,
+            shift: 0.0,
+            drift: 0.0,
+        }
+    }
+}
+
+/// A pitch point in milliseconds from the start of the note and tenths of a semitone
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxPitchPoint {
+    pub x: f32,
+    pub y: f32,
+    pub shape: String,
+}
+
+/// A phoneme the user picked by hand instead of the phonemizer's choice
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxPhonemeOverride {
+    pub index: i32,
+    pub phoneme: Option<String>,
+}
+
+/// A curve drawn over a part, with points in ticks relative to the part
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UstxCurve {
+    pub abbr: String,
+    pub xs: Vec<i64>,
+    pub ys: Vec<f32>,
+}
+
+impl UstxProject {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        serde_yaml::from_str(&text).map_err(|err| err.to_string())
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let text = serde_yaml::to_string(self).map_err(|err| err.to_string())?;
+        std::fs::write(path, text).map_err(|err| err.to_string())
+    }
+
+    /// Numbers of the tracks which have notes, in order
+    pub fn tracks(&self) -> Vec<usize> {
+        let mut tracks: Vec<usize> = self
+            .voice_parts
+            .iter()
+            .filter(|part| !part.notes.is_empty())
+            .map(|part| part.track_no)
+            .collect();
+        tracks.sort();
+        tracks.dedup();
+        tracks
+    }
+
+    /// Tempo at a position in ticks
+    fn bpm_at(&self, tick: i64) -> f64 {
+        self.tempos
+            .iter()
+            .filter(|tempo| tempo.position <= tick)
+            .max_by_key(|tempo| tempo.position)
+            .or(self.tempos.first())
+            .map_or(self.bpm, |tempo| tempo.bpm)
+            .max(1.0)
+    }
+
+    /// Lyrics, phoneme overrides and pitch of the notes on a track, keyed by the notes' positions
+    pub fn timeline(&self, track: usize) -> LyricTimeline {
+        let mut timeline = LyricTimeline::new();
+        for part in self
+            .voice_parts
+            .iter()
+            .filter(|part| part.track_no == track)
+        {
+            let deviation = part.curves.iter().find(|curve| curve.abbr == "pitd");
+            for note in part.notes.iter() {
+                // `+` notes carry the previous note's vowel on, they don't start a new lyric
+                if note.lyric.starts_with('+') || note.lyric.trim().is_empty() {
+                    continue;
+                }
+                let tick = part.position + note.position;
+                let ms_per_tick = 60_000.0 / self.bpm_at(tick) / self.resolution as f64;
+                let lyric = note
+                    .phoneme_overrides
+                    .iter()
+                    .find(|phoneme| phoneme.index == 0)
+                    .and_then(|phoneme| phoneme.phoneme.clone())
+                    .unwrap_or_else(|| note.lyric.clone());
+                let pitch_bend = (!note.pitch.data.is_empty()).then(|| PitchBend {
+                    points: note
+                        .pitch
+                        .data
+                        .iter()
+                        .map(|point| BendPoint {
+                            time: point.x,
+                            cents: point.y * 10.0,
+                            shape: BendShape::from_ustx(&point.shape),
+                        })
+                        .collect(),
+                });
+                timeline.insert(TimedLyric {
+                    beat: tick as f64 / self.resolution as f64,
+                    lyric: lyric.trim().to_string(),
+                    flags: Flags::default(),
+                    expression: NoteExpression {
+                        pitch_bend,
+                        pitch_deviation: deviation
+                            .and_then(|curve| note_deviation(curve, note, ms_per_tick)),
+                        ..Default::default()
+                    },
+                });
+            }
+        }
+        timeline
+    }
+}
+
+/// The part of a `pitd` curve under a note, in milliseconds from its start and cents.
+/// `None` if the curve is flat there.
+fn note_deviation(curve: &UstxCurve, note: &UstxNote, ms_per_tick: f64) -> Option<PitchBend> {
+    let points: Vec<BendPoint> = curve
+        .xs
+        .iter()
+        .zip(curve.ys.iter())
+        .filter(|(x, _)| **x >= note.position && **x <= note.position + note.duration)
+        .map(|(x, y)| BendPoint {
+            time: ((x - note.position) as f64 * ms_per_tick) as f32,
+            cents: *y,
+            shape: BendShape::Linear,
+        })
+        .collect();
+    points
+        .iter()
+        .any(|point| point.cents != 0.0)
+        .then_some(PitchBend { points })
+}
+
+/// Lyrics and pitch imported from one track of an OpenUtau project
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UstxLyric {
+    pub path: PathBuf,
+    /// Numbers of the project's tracks with notes, with their timelines
+    pub tracks: Vec<(usize, LyricTimeline)>,
+    /// Which of `tracks` to sing, set from the USTX Track param
+    #[serde(skip)]
+    pub track: usize,
+    /// Song position of the note being started, `None` when the transport isn't playing
+    #[serde(skip)]
+    position: Option<f64>,
+    /// Next note when there is no song position to look it up by
+    #[serde(skip)]
+    pub index: usize,
+    #[serde(skip)]
+    current: Option<TimedLyric>,
+}
+
+impl UstxLyric {
+    pub fn new(path: PathBuf) -> Self {
+        let tracks = match UstxProject::read(&path) {
+            Ok(project) => project
+                .tracks()
+                .into_iter()
+                .map(|track| (track, project.timeline(track)))
+                .collect(),
+            Err(err) => {
+                nih_log!("Failed to read USTX {}: {}", path.display(), err);
+                vec![]
+            }
+        };
+        Self {
+            path,
+            tracks,
+            ..Default::default()
+        }
+    }
+
+    /// Timeline of the chosen track, the last one if there are fewer tracks
+    pub fn timeline(&self) -> Option<&LyricTimeline> {
+        self.tracks
+            .get(self.track.min(self.tracks.len().saturating_sub(1)))
+            .map(|(_, timeline)| timeline)
+    }
+}
+
+impl Lyric for UstxLyric {
+    fn get_jpn_utf8(&mut self) -> String {
+        let (position, mut index) = (self.position, self.index);
+        self.current = self
+            .timeline()
+            .and_then(|timeline| timeline.next(position, &mut index))
+            .cloned();
+        self.index = index;
+        self.current
+            .as_ref()
+            .map(|entry| entry.lyric.clone())
+            .unwrap_or_default()
+    }
+
+    fn get_latin(&mut self) -> String {
+        romaji::to_romaji(&self.get_jpn_utf8())
+    }
+
+    fn get_expression(&mut self) -> NoteExpression {
+        self.current
+            .as_ref()
+            .map(|entry| entry.expression.clone())
+            .unwrap_or_default()
+    }
+
+    fn set_position(&mut self, beat: Option<f64>) {
+        self.position = beat;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USTX: &str = "
+name: test
+resolution: 480
+bpm: 120
+tempos:
+- position: 0
+  bpm: 120
+- position: 960
+  bpm: 60
+voice_parts:
+- track_no: 1
+  position: 480
+  notes:
+  - position: 0
+    duration: 480
+    tone: 60
+    lyric: あ
+    pitch:
+      data:
+      - {x: -25, y: -20, shape: io}
+      - {x: 0, y: 0, shape: l}
+  - position: 480
+    duration: 480
+    tone: 62
+    lyric: +
+  - position: 960
+    duration: 480
+    tone: 62
+    lyric: か
+    phoneme_overrides:
+    - {index: 0, phoneme: が}
+  curves:
+  - abbr: pitd
+    xs: [960, 1200, 1440]
+    ys: [0, 50, 0]
+- track_no: 0
+  position: 0
+  notes:
+  - {position: 0, duration: 480, tone: 60, lyric: さ}
+- track_no: 2
+  position: 0
+";
+
+    fn project() -> UstxProject {
+        serde_yaml::from_str(USTX).unwrap()
+    }
+
+    #[test]
+    fn reads_tracks_with_notes() {
+        let project = project();
+        assert_eq!(project.name, "test");
+        assert_eq!(project.tracks(), [0, 1]);
+        assert_eq!(project.bpm_at(0), 120.0);
+        assert_eq!(project.bpm_at(1000), 60.0);
+    }
+
+    #[test]
+    fn timeline_follows_parts_and_overrides() {
+        let timeline = project().timeline(1);
+        let lyrics: Vec<(f64, &str)> = timeline
+            .entries()
+            .iter()
+            .map(|entry| (entry.beat, entry.lyric.as_str()))
+            .collect();
+        // the `+` note carries あ on, the override replaces か
+        assert_eq!(lyrics, [(1.0, "あ"), (3.0, "が")]);
+    }
+
+    #[test]
+    fn imports_pitch_bends_and_deviation() {
+        let timeline = project().timeline(1);
+        let first = &timeline.entries()[0].expression;
+        let bend = first.pitch_bend.as_ref().unwrap();
+        assert_eq!(bend.points[0].time, -25.0);
+        assert_eq!(bend.points[0].cents, -200.0);
+        assert!(first.pitch_deviation.is_none());
+
+        // the curve peaks half a beat into the note, where the tempo is 60 BPM
+        let deviation = timeline.entries()[1].expression.pitch_deviation.clone();
+        let points: Vec<(f32, f32)> = deviation
+            .unwrap()
+            .points
+            .iter()
+            .map(|point| (point.time, point.cents))
+            .collect();
+        assert_eq!(points, [(0.0, 0.0), (500.0, 50.0), (1000.0, 0.0)]);
+    }
+
+    #[test]
+    fn missing_fields_get_defaults() {
+        let project: UstxProject = serde_yaml::from_str("name: empty").unwrap();
+        assert_eq!(project.resolution, 480);
+        assert_eq!(project.bpm_at(0), 120.0);
+        assert!(project.tracks().is_empty());
+    }
+
+    #[test]
+    fn track_param_past_the_last_track_sings_the_last() {
+        let project = project();
+        let lyric = UstxLyric {
+            tracks: project
+                .tracks()
+                .into_iter()
+                .map(|track| (track, project.timeline(track)))
+                .collect(),
+            track: 5,
+            ..Default::default()
+        };
+        assert_eq!(lyric.timeline().unwrap().text(), "あ が");
+    }
+}
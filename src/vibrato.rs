@@ -0,0 +1,85 @@
+use nih_plug::prelude::Enum;
+
+/// Note lengths the vibrato rate can be synced to
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VibratoDivision {
+    #[name = "1/4"]
+    Quarter,
+    #[name = "1/8"]
+    Eighth,
+    #[name = "1/8T"]
+    EighthTriplet,
+    #[name = "1/16"]
+    Sixteenth,
+    #[name = "1/16T"]
+    SixteenthTriplet,
+    #[name = "1/32"]
+    ThirtySecond,
+}
+
+impl VibratoDivision {
+    /// Length of one vibrato cycle in beats
+    pub fn beats(&self) -> f32 {
+        match self {
+            VibratoDivision::Quarter => 1.0,
+            VibratoDivision::Eighth => 0.5,
+            VibratoDivision::EighthTriplet => 1.0 / 3.0,
+            VibratoDivision::Sixteenth => 0.25,
+            VibratoDivision::SixteenthTriplet => 1.0 / 6.0,
+            VibratoDivision::ThirtySecond => 0.125,
+        }
+    }
+
+    /// Vibrato rate in Hz at the given tempo
+    pub fn rate(&self, tempo: f32) -> f32 {
+        tempo / 60.0 / self.beats()
+    }
+}
+
+/// Settings shared by every voice's vibrato, read from the params once per block
+#[derive(Debug, Clone, Copy)]
+pub struct VibratoSettings {
+    /// Rate in Hz
+    pub rate: f32,
+    /// Depth in cents
+    pub depth: f32,
+    /// Time before the vibrato starts, in seconds
+    pub delay: f32,
+    /// Time the vibrato takes to reach full depth after the delay, in seconds
+    pub fade_in: f32,
+}
+
+/// Per-voice vibrato LFO
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Vibrato {
+    phase: f32,
+    /// Seconds since the voice started
+    age: f32,
+}
+
+impl Vibrato {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current pitch offset in cents
+    pub fn cents(&self, settings: &VibratoSettings) -> f32 {
+        if self.age < settings.delay {
+            return 0.0;
+        }
+        let fade = if settings.fade_in > 0.0 {
+            ((self.age - settings.delay) / settings.fade_in).min(1.0)
+        } else {
+            1.0
+        };
+        (self.phase * std::f32::consts::TAU).sin() * settings.depth * fade
+    }
+
+    /// Moves the LFO forward by `seconds`
+    pub fn advance(&mut self, seconds: f32, settings: &VibratoSettings) {
+        self.age += seconds;
+        if self.age >= settings.delay {
+            self.phase = (self.phase + seconds * settings.rate).fract();
+        }
+    }
+}